@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where a managed game lives, written by `modulate init` and read by every other command.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Config {
+    pub working_dir: PathBuf,
+    pub bak_dir: PathBuf,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {} ({}), run `modulate init` first", path.display(), e))?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+}
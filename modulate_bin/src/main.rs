@@ -1,20 +1,477 @@
-use modulate_lib::ModManager;
+mod config;
 
-fn main() {
-    pretty_env_logger::init();
+use crate::config::Config;
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use modulate_lib::r#mod::{ModMetadata, OptionKind};
+use modulate_lib::{
+    ChangeKind, ConflictReport, DeployPlan, DeployStrategy, ModManager, Recovery, Resolution, VanillaChangeKind,
+};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use uuid::Uuid;
 
-    let mut manager = ModManager::new("./examples/working_dir".parse().unwrap(), "./examples/bak_dir".parse().unwrap()).unwrap();
+/// Manage the mods deployed into a game directory.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    /// The configuration written by `init`.
+    #[arg(short, long, global = true, default_value = "modulate.toml")]
+    config: PathBuf,
+    /// Log more; repeat for even more.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Start managing a game directory.
+    Init {
+        working_dir: PathBuf,
+        /// Where to keep backups of the game files mods replace. Defaults to `modulate_bak` next to the
+        /// configuration.
+        #[arg(long)]
+        bak_dir: Option<PathBuf>,
+    },
+    #[command(flatten)]
+    Manage(ManageCommand),
+}
+
+/// The commands that work on a game directory set up by `init`.
+#[derive(Debug, Subcommand)]
+enum ManageCommand {
+    /// Register a mod directory, or install a mod from a zip or tar archive. The mod starts out disabled.
+    Add { path: PathBuf },
+    /// Unregister a disabled mod. A mod installed from an archive is deleted as well.
+    Remove { r#mod: String },
+    /// List the enabled mods in order, then the disabled ones.
+    List,
+    /// Enable a mod, at the end of the order.
+    Enable { r#mod: String },
+    /// Disable a mod.
+    Disable { r#mod: String },
+    /// Show or change the order of the enabled mods. Earlier mods win conflicts.
+    Order {
+        #[command(subcommand)]
+        action: Option<OrderAction>,
+    },
+    /// Deploy the enabled mods into the game directory.
+    Deploy {
+        /// Only print what would be done.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Remove every deployed mod file and restore the original game files. The enabled mods stay enabled
+    /// and come back with the next deploy.
+    Purge,
+    /// Show or change how mod files are deployed: hardlink, symlink, absolute-symlink, copy or reflink.
+    Strategy {
+        strategy: Option<DeployStrategy>,
+        /// Change the strategy of one mod instead of the default.
+        #[arg(long = "mod")]
+        r#mod: Option<String>,
+        /// Make the mod use the default strategy again.
+        #[arg(long, requires = "mod", conflicts_with = "strategy")]
+        reset: bool,
+    },
+    /// Show or set globs of files not to deploy from a mod, such as `*.txt` or `docs/`.
+    Ignore {
+        r#mod: String,
+        /// Replace the mod's ignore rules with these.
+        patterns: Vec<String>,
+        /// Remove all of the mod's ignore rules.
+        #[arg(long, conflicts_with = "patterns")]
+        clear: bool,
+    },
+    /// Show the option groups of a mod, or choose which options of a group are deployed.
+    Options {
+        r#mod: String,
+        group: Option<String>,
+        /// The names of the options to choose.
+        #[arg(requires = "group")]
+        choices: Vec<String>,
+        /// Go back to the default options of the group.
+        #[arg(long, requires = "group", conflicts_with = "choices")]
+        reset: bool,
+    },
+    /// Show or change whether paths that only differ in case are merged, for games built for Windows.
+    CaseInsensitive { enabled: Option<bool> },
+    /// Check whether deployed files were changed since they were deployed, by the game or anything else.
+    Verify {
+        /// Throw the changes away and deploy the mod files again.
+        #[arg(long, conflicts_with = "move_to")]
+        restore: bool,
+        /// Move the changed files into this mod, then deploy the mod files again.
+        #[arg(long)]
+        move_to: Option<String>,
+    },
+    /// Check the game files against those recorded by `init`, for example after a game update.
+    Vanilla {
+        /// Accept the changes as the new game files, replacing outdated backups.
+        #[arg(long)]
+        refresh: bool,
+    },
+    /// Move files the game created or changed into the overwrite mod, which always loads last.
+    Capture,
+    /// Show files that more than one enabled mod provides.
+    Conflicts,
+    /// Show the deployed files and the mod each comes from.
+    Tree,
+}
+
+#[derive(Debug, Subcommand)]
+enum OrderAction {
+    /// Set the whole order at once.
+    Set { mods: Vec<String> },
+    /// Move a mod to a position, starting at 1.
+    Move { r#mod: String, position: usize },
+    /// Move a mod right before another one.
+    Before { r#mod: String, other: String },
+    /// Move a mod right after another one.
+    After { r#mod: String, other: String },
+    /// Sort the mods by their load order rules.
+    Sort,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let level = match cli.verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        _ => LevelFilter::Trace,
+    };
+    pretty_env_logger::formatted_builder().filter_level(level).init();
+
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    match cli.command {
+        Command::Init { working_dir, bak_dir } => init(&cli.config, working_dir, bak_dir),
+        Command::Manage(command) => manage(open(&cli.config)?, command),
+    }
+}
 
-    let mod1 = manager.add_mod("./examples/mod1".into()).unwrap();
-    let mod2 = manager.add_mod("./examples/mod2".into()).unwrap();
+fn manage(mut manager: ModManager, command: ManageCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        ManageCommand::Add { path } => {
+            let uuid = if path.is_file() {
+                manager.install_archive(path)?
+            } else {
+                manager.add_mod(path)?
+            };
+            println!("Added {}", describe(&manager, uuid));
+        }
+        ManageCommand::Remove { r#mod } => {
+            let uuid = resolve(&manager, &r#mod)?;
+            let description = describe(&manager, uuid);
+            manager.remove_mod(uuid)?;
+            println!("Removed {}", description);
+        }
+        ManageCommand::List => list(&manager),
+        ManageCommand::Enable { r#mod } => {
+            let uuid = resolve(&manager, &r#mod)?;
+            manager.activate_mod(uuid)?;
+            println!("Enabled {}", describe(&manager, uuid));
+        }
+        ManageCommand::Disable { r#mod } => {
+            let uuid = resolve(&manager, &r#mod)?;
+            manager.deactivate_mod(uuid)?;
+            println!("Disabled {}", describe(&manager, uuid));
+        }
+        ManageCommand::Order { action } => {
+            match action {
+                None => {}
+                Some(OrderAction::Set { mods }) => {
+                    let order = mods
+                        .iter()
+                        .map(|r#mod| resolve(&manager, r#mod))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    manager.set_order(&order)?;
+                }
+                Some(OrderAction::Move { r#mod, position }) => {
+                    let uuid = resolve(&manager, &r#mod)?;
+                    manager.move_mod(uuid, position.saturating_sub(1))?;
+                }
+                Some(OrderAction::Before { r#mod, other }) => {
+                    let (uuid, other) = (resolve(&manager, &r#mod)?, resolve(&manager, &other)?);
+                    manager.move_before(uuid, other)?;
+                }
+                Some(OrderAction::After { r#mod, other }) => {
+                    let (uuid, other) = (resolve(&manager, &r#mod)?, resolve(&manager, &other)?);
+                    manager.move_after(uuid, other)?;
+                }
+                Some(OrderAction::Sort) => manager.sort_mods()?,
+            }
+            for (i, metadata) in manager.active_mods().iter().enumerate() {
+                println!("{:>3}. {} {} ({})", i + 1, metadata.name, metadata.version, metadata.uuid);
+            }
+        }
+        ManageCommand::Deploy { dry_run } => {
+            let plan = manager.plan_deploy()?;
+            for rejected in &plan.rejected_hunks {
+                eprintln!(
+                    "Hunk {} of {} ({}) doesn't apply to {}",
+                    rejected.hunk,
+                    rejected.patch.display(),
+                    rejected.mod_name,
+                    rejected.target.display()
+                );
+            }
+            if plan.is_empty() {
+                println!("Nothing to deploy");
+            } else if dry_run {
+                print_plan(&plan);
+            } else {
+                let count = plan.operations.len();
+                manager.apply_plan(plan)?;
+                println!("Deployed ({} operations)", count);
+            }
+        }
+        ManageCommand::Purge => {
+            manager.purge()?;
+            println!("Purged");
+        }
+        ManageCommand::Strategy { strategy, r#mod, reset } => {
+            match (r#mod, strategy) {
+                (Some(r#mod), strategy) if reset || strategy.is_some() => {
+                    let uuid = resolve(&manager, &r#mod)?;
+                    manager.set_mod_deploy_strategy(uuid, strategy)?;
+                }
+                (None, Some(strategy)) => manager.set_deploy_strategy(strategy)?,
+                _ => {}
+            }
+            println!("Default: {}", manager.deploy_strategy());
+            for metadata in manager.active_mods().into_iter().chain(manager.inactive_mods()) {
+                if let Some(strategy) = manager.mod_deploy_strategy(metadata.uuid) {
+                    println!("{}: {}", metadata.name, strategy);
+                }
+            }
+        }
+        ManageCommand::Ignore { r#mod, patterns, clear } => {
+            let uuid = resolve(&manager, &r#mod)?;
+            if clear || !patterns.is_empty() {
+                manager.set_mod_ignore_rules(uuid, patterns)?;
+            }
+            for rule in manager.mod_ignore_rules(uuid)? {
+                println!("{}", rule);
+            }
+        }
+        ManageCommand::Options { r#mod, group, choices, reset } => {
+            let uuid = resolve(&manager, &r#mod)?;
+            if let Some(group) = group {
+                if reset || !choices.is_empty() {
+                    manager.set_mod_choice(uuid, &group, (!reset).then_some(choices))?;
+                }
+            }
+            let chosen = manager.mod_choices(uuid)?;
+            for group in manager.mod_metadata(uuid).into_iter().flat_map(|metadata| &metadata.options) {
+                let kind = match group.kind {
+                    OptionKind::Single => "one of",
+                    OptionKind::Multi => "any of",
+                };
+                println!("{} ({}):", group.name, kind);
+                for choice in &group.choices {
+                    let mark = if chosen[&group.name].contains(&choice.name) { "*" } else { " " };
+                    println!("  {} {}", mark, choice.name);
+                }
+            }
+        }
+        ManageCommand::CaseInsensitive { enabled } => {
+            if let Some(enabled) = enabled {
+                manager.set_case_insensitive(enabled)?;
+            }
+            println!("Case-insensitive: {}", if manager.case_insensitive() { "yes" } else { "no" });
+        }
+        ManageCommand::Verify { restore, move_to } => {
+            let resolution = match move_to {
+                Some(r#mod) => Some(Resolution::MoveTo(resolve(&manager, &r#mod)?)),
+                None if restore => Some(Resolution::Restore),
+                None => None,
+            };
+            let changes = manager.verify()?;
+            if changes.is_empty() {
+                println!("No deployed file was changed");
+            }
+            for change in changes {
+                let what = match change.kind {
+                    ChangeKind::Removed => "removed",
+                    ChangeKind::Replaced => "replaced",
+                    ChangeKind::Edited { source_modified: false } => "edited",
+                    ChangeKind::Edited { source_modified: true } => "edited, along with the mod file",
+                };
+                println!("{}: {} ({})", change.path, what, name(&manager, change.source));
+                let Some(resolution) = resolution else {
+                    continue;
+                };
+                if change.kind == ChangeKind::Removed && matches!(resolution, Resolution::MoveTo(_)) {
+                    continue;
+                }
+                match manager.resolve_change(&change.path, resolution) {
+                    Ok(()) if resolution == Resolution::Restore => println!("  restored"),
+                    Ok(()) => println!("  moved"),
+                    Err(e) => println!("  {}", e),
+                }
+            }
+        }
+        ManageCommand::Vanilla { refresh } => {
+            let changes = if refresh {
+                manager.refresh_vanilla()?
+            } else {
+                manager.vanilla_changes()?
+            };
+            if changes.is_empty() {
+                println!("The game files are unchanged");
+            }
+            for change in &changes {
+                let what = match change.kind {
+                    VanillaChangeKind::Modified => "modified",
+                    VanillaChangeKind::Removed => "removed",
+                    VanillaChangeKind::Added => "added",
+                    VanillaChangeKind::BackupStale => "replaced under a mod, backup outdated",
+                };
+                println!("{}: {}", change.path, what);
+            }
+            if refresh && !changes.is_empty() {
+                println!("Refreshed {} game files", changes.len());
+            }
+        }
+        ManageCommand::Capture => {
+            let captured = manager.capture_new_files()?;
+            for path in &captured {
+                println!("{}", path);
+            }
+            let overwrite = manager.overwrite_mod();
+            let dir = manager.mod_dir(overwrite).unwrap().display();
+            println!("Captured {} files into {}", captured.len(), dir);
+        }
+        ManageCommand::Conflicts => print_conflicts(&manager, &manager.conflicts()),
+        ManageCommand::Tree => manager.print_tree(),
+    }
+    Ok(())
+}
+
+fn init(config_path: &Path, working_dir: PathBuf, bak_dir: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    if config_path.exists() {
+        return Err(format!("{} already exists", config_path.display()).into());
+    }
+    let bak_dir = bak_dir.unwrap_or_else(|| config_path.with_file_name("modulate_bak"));
+    ModManager::new(working_dir.clone(), bak_dir.clone())?;
+    let config = Config {
+        working_dir: fs::canonicalize(working_dir)?,
+        bak_dir: fs::canonicalize(bak_dir)?,
+    };
+    config.save(config_path)?;
+    println!("Managing {}", config.working_dir.display());
+    Ok(())
+}
+
+fn open(config_path: &Path) -> Result<ModManager, Box<dyn Error>> {
+    let config = Config::load(config_path)?;
+    let manager = ModManager::new(config.working_dir, config.bak_dir)?;
+    match manager.recovery() {
+        Some(Recovery::Finished(ops)) => {
+            eprintln!("Finished an interrupted deploy ({} operations)", ops.len())
+        }
+        Some(Recovery::RolledBack(ops)) => {
+            eprintln!("Rolled back an interrupted deploy ({} operations)", ops.len())
+        }
+        None => {}
+    }
+    Ok(manager)
+}
+
+/// Find a mod by uuid or by name.
+fn resolve(manager: &ModManager, query: &str) -> Result<Uuid, Box<dyn Error>> {
+    if let Ok(uuid) = query.parse::<Uuid>() {
+        return Ok(uuid);
+    }
+    let matches: Vec<Uuid> = manager
+        .active_mods()
+        .into_iter()
+        .chain(manager.inactive_mods())
+        .chain(manager.mod_metadata(manager.overwrite_mod()))
+        .filter(|metadata| metadata.name == query)
+        .map(|metadata| metadata.uuid)
+        .collect();
+    match matches.as_slice() {
+        [uuid] => Ok(*uuid),
+        [] => Err(format!("no mod named {}", query).into()),
+        _ => Err(format!("more than one mod is named {}, use its uuid", query).into()),
+    }
+}
+
+fn name(manager: &ModManager, uuid: Uuid) -> String {
+    manager
+        .mod_metadata(uuid)
+        .map_or_else(|| uuid.to_string(), |metadata| metadata.name.clone())
+}
 
-    manager.activate_mod(mod1).unwrap();
-    manager.activate_mod(mod2).unwrap();
+fn describe(manager: &ModManager, uuid: Uuid) -> String {
+    match manager.mod_metadata(uuid) {
+        Some(metadata) => format!("{} {} ({})", metadata.name, metadata.version, metadata.uuid),
+        None => uuid.to_string(),
+    }
+}
 
-    manager.deploy_mods();
+fn list(manager: &ModManager) {
+    let load_error = |metadata: &ModMetadata| match manager.mod_load_error(metadata.uuid) {
+        Some(error) => format!(" - couldn't load: {}", error),
+        None => String::new(),
+    };
+    println!("Enabled:");
+    for (i, metadata) in manager.active_mods().iter().enumerate() {
+        println!("{:>3}. {} {} ({}){}", i + 1, metadata.name, metadata.version, metadata.uuid, load_error(metadata));
+    }
+    println!("Disabled:");
+    for metadata in manager.inactive_mods() {
+        println!("     {} {} ({}){}", metadata.name, metadata.version, metadata.uuid, load_error(metadata));
+    }
+}
 
-    manager.deactivate_mod(mod1).unwrap();
-    manager.deactivate_mod(mod2).unwrap();
+fn print_plan(plan: &DeployPlan) {
+    for step in &plan.operations {
+        match &step.source {
+            Some(source) => println!("{} ({})", step.operation, source.mod_name),
+            None => println!("{}", step.operation),
+        }
+    }
+}
 
-    manager.deploy_mods();
+fn print_conflicts(manager: &ModManager, report: &ConflictReport) {
+    if report.files.is_empty() {
+        println!("No conflicts");
+        return;
+    }
+    for conflict in &report.files {
+        let providers: Vec<String> = conflict.providers.iter().map(|&uuid| name(manager, uuid)).collect();
+        let mismatch = if conflict.type_mismatch { ", file and dir" } else { "" };
+        println!(
+            "{}: {} (winner: {}{})",
+            conflict.path,
+            providers.join(", "),
+            name(manager, conflict.winner),
+            mismatch
+        );
+    }
+    println!();
+    for summary in &report.mods {
+        println!("{}:", name(manager, summary.uuid));
+        for &(other, files) in &summary.overrides {
+            println!("  overrides {} files of {}", files, name(manager, other));
+        }
+        for &(other, files) in &summary.overridden_by {
+            println!("  overridden by {} on {} files", name(manager, other), files);
+        }
+    }
 }
@@ -0,0 +1,149 @@
+use crate::{IoResultExt, ModError};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The archive formats mods can be installed from, recognized by file name.
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Zip,
+    Tar,
+    TarGz,
+    TarZst,
+}
+
+impl Format {
+    fn of(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_lowercase();
+        if name.ends_with(".zip") {
+            Some(Format::Zip)
+        } else if name.ends_with(".tar") {
+            Some(Format::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Format::TarGz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Format::TarZst)
+        } else {
+            None
+        }
+    }
+}
+
+/// Unpack `archive` into `dest`, which must not exist yet. Entries that would end up outside of `dest`
+/// are rejected by both the zip and the tar readers.
+pub(crate) fn unpack(archive: &Path, dest: &Path) -> Result<(), ModError> {
+    let format = Format::of(archive).ok_or_else(|| ModError::UnsupportedArchive(archive.to_path_buf()))?;
+    let file = fs::File::open(archive).at(archive)?;
+    fs::create_dir_all(dest).at(dest)?;
+    let reader = io::BufReader::new(file);
+    match format {
+        Format::Zip => zip::ZipArchive::new(reader)
+            .and_then(|mut zip| zip.extract(dest))
+            .map_err(|source| ModError::ArchiveFailed {
+                path: archive.to_path_buf(),
+                source,
+            }),
+        Format::Tar => tar::Archive::new(reader).unpack(dest).at(archive),
+        Format::TarGz => tar::Archive::new(flate2::read::GzDecoder::new(reader)).unpack(dest).at(archive),
+        Format::TarZst => {
+            let decoder = zstd::Decoder::with_buffer(reader).at(archive)?;
+            tar::Archive::new(decoder).unpack(dest).at(archive)
+        }
+    }
+}
+
+/// Find the mod in an unpacked archive: either `mod.toml` is at the top, or the archive holds a single
+/// folder with `mod.toml` in it.
+pub(crate) fn find_mod_root(dir: &Path) -> Result<PathBuf, ModError> {
+    if dir.join("mod.toml").is_file() {
+        return Ok(dir.to_path_buf());
+    }
+    let entries = fs::read_dir(dir)
+        .at(dir)?
+        .collect::<Result<Vec<_>, _>>()
+        .at(dir)?;
+    match entries.as_slice() {
+        [entry] if entry.path().join("mod.toml").is_file() => Ok(entry.path()),
+        _ => Err(ModError::ModMetadataMissing(dir.to_string_lossy().to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::io::Write;
+
+    const METADATA: &str = "name = \"mod\"\nversion = \"1.0.0\"\nuuid = \"00000000-0000-0000-0000-000000000001\"\n";
+
+    fn write_zip(path: &Path, files: &[(&str, &str)]) {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        for (name, contents) in files {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn write_tar_gz(path: &Path, files: &[(&str, &str)]) {
+        let encoder = flate2::write::GzEncoder::new(fs::File::create(path).unwrap(), flate2::Compression::default());
+        let mut tar = tar::Builder::new(encoder);
+        for (name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, contents.as_bytes()).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn finds_mod_in_a_single_top_level_folder() {
+        let tmp = TempDir::new("archive-folder");
+        let dir = tmp.path();
+        let archive = dir.join("mod-1.0.TAR.GZ");
+        write_tar_gz(&archive, &[("mod-1.0/mod.toml", METADATA), ("mod-1.0/data/a.txt", "a")]);
+        let dest = dir.join("unpacked");
+        unpack(&archive, &dest).unwrap();
+        let root = find_mod_root(&dest).unwrap();
+        assert_eq!(root, dest.join("mod-1.0"));
+        assert_eq!(fs::read_to_string(root.join("data/a.txt")).unwrap(), "a");
+    }
+
+    #[test]
+    fn finds_mod_at_the_top_of_a_flat_archive() {
+        let tmp = TempDir::new("archive-flat");
+        let dir = tmp.path();
+        let archive = dir.join("mod.zip");
+        write_zip(&archive, &[("mod.toml", METADATA), ("a.txt", "a"), ("data/b.txt", "b")]);
+        let dest = dir.join("unpacked");
+        unpack(&archive, &dest).unwrap();
+        assert_eq!(find_mod_root(&dest).unwrap(), dest);
+        assert_eq!(fs::read_to_string(dest.join("data/b.txt")).unwrap(), "b");
+    }
+
+    #[test]
+    fn needs_mod_toml_at_the_top_or_in_a_single_folder() {
+        let tmp = TempDir::new("archive-no-root");
+        let dir = tmp.path();
+        let archive = dir.join("mods.zip");
+        write_zip(&archive, &[("one/mod.toml", METADATA), ("two/mod.toml", METADATA)]);
+        let dest = dir.join("unpacked");
+        unpack(&archive, &dest).unwrap();
+        let result = find_mod_root(&dest);
+        assert!(matches!(result, Err(ModError::ModMetadataMissing(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn rejects_unsupported_formats() {
+        let tmp = TempDir::new("archive-unsupported");
+        let dir = tmp.path();
+        let archive = dir.join("mod.rar");
+        fs::write(&archive, "rar").unwrap();
+        let dest = dir.join("unpacked");
+        let result = unpack(&archive, &dest);
+        assert!(matches!(result, Err(ModError::UnsupportedArchive(_))), "{:?}", result);
+        assert!(!dest.exists());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{IoResultExt, ModError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// The files the working directory had before any mod was deployed into it. Anything else that shows up
+/// in it, and isn't deployed, was created by the game or some other tool.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Baseline {
+    pub(crate) files: BTreeMap<String, VanillaFile>,
+    /// The dirs the working directory had before mods deployed into them, which are never removed, even
+    /// once no mod has files in them anymore.
+    pub(crate) dirs: BTreeSet<String>,
+}
+
+/// A vanilla file as it was recorded. The modification time only serves to skip hashing files that are
+/// obviously unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct VanillaFile {
+    pub(crate) size: u64,
+    pub(crate) modified: Option<SystemTime>,
+    pub(crate) hash: [u8; 32],
+}
+
+impl VanillaFile {
+    pub(crate) fn of(path: &Path) -> Result<Self, ModError> {
+        let metadata = fs::symlink_metadata(path).at(path)?;
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            hash: hash_file(path, &metadata).at(path)?,
+        })
+    }
+
+    /// Whether the file at `path` still has the recorded contents. `None` if it is gone.
+    pub(crate) fn matches(&self, path: &Path) -> Result<Option<bool>, ModError> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).at(path),
+        };
+        if metadata.len() != self.size {
+            return Ok(Some(false));
+        }
+        if metadata.modified().ok() == self.modified {
+            return Ok(Some(true));
+        }
+        Ok(Some(hash_file(path, &metadata).at(path)? == self.hash))
+    }
+}
+
+/// A vanilla file that changed since it was recorded, found by
+/// [`ModManager::vanilla_changes`](crate::ModManager::vanilla_changes). Usually the result of a game
+/// update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VanillaChange {
+    /// The path in the working directory, starting with a `/`.
+    pub path: String,
+    pub kind: VanillaChangeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VanillaChangeKind {
+    /// The vanilla file, which no mod replaces, has different contents.
+    Modified,
+    /// The vanilla file, which no mod replaces, is gone.
+    Removed,
+    /// A file that is neither a vanilla file nor deployed showed up, such as one a game update added. Files
+    /// the game writes while it runs, like saves, look the same.
+    Added,
+    /// A mod replaces the vanilla file, and something put a new file in place of the deployed one. If
+    /// that was a game update, the backup of the vanilla file is outdated and purging would bring it back.
+    BackupStale,
+}
+
+impl Baseline {
+    pub(crate) fn load(path: &Path) -> Result<Option<Self>, ModError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = fs::File::open(path).at(path)?;
+        let len = file.metadata().at(path)?.len();
+        crate::deserialize_limited(io::BufReader::new(file), len)
+            .map(Some)
+            .map_err(|e| ModError::StateLoadFailed(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Written the same way as the manager state, through a temporary file.
+    pub(crate) fn save(&self, path: &Path) -> Result<(), ModError> {
+        let tmp_path = path.with_extension("vanilla.tmp");
+        let file = fs::File::create(&tmp_path).at(&tmp_path)?;
+        let mut writer = io::BufWriter::new(&file);
+        bincode::serialize_into(&mut writer, self)
+            .map_err(|e| ModError::StateSaveFailed(format!("{}: {}", path.display(), e)))?;
+        writer.flush().at(&tmp_path)?;
+        drop(writer);
+        file.sync_all().at(&tmp_path)?;
+        fs::rename(&tmp_path, path).at(path)
+    }
+}
+
+/// Collect the path of every file below `dir`, relative to it and starting with a `/`, in order. Symlinks
+/// count as files and are not followed.
+pub(crate) fn walk_files(dir: &Path, current_path: &str, files: &mut Vec<String>) -> Result<(), ModError> {
+    walk(dir, current_path, files, &mut Vec::new())
+}
+
+/// Like [`walk_files`], also collecting the path of every dir below `dir`.
+pub(crate) fn walk(dir: &Path, current_path: &str, files: &mut Vec<String>, dirs: &mut Vec<String>) -> Result<(), ModError> {
+    let mut entries = fs::read_dir(dir)
+        .at(dir)?
+        .collect::<Result<Vec<_>, _>>()
+        .at(dir)?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|_| ModError::InvalidFileName(path.clone()))?;
+        let relative = format!("{}/{}", current_path, name);
+        if entry.file_type().at(&path)?.is_dir() {
+            walk(&path, &relative, files, dirs)?;
+            dirs.push(relative);
+        } else {
+            files.push(relative);
+        }
+    }
+    Ok(())
+}
+
+/// Hash the contents of the file at `path`, or where it points if it is a symlink. Symlinks aren't
+/// followed, since they may point to a dir or out of the working directory.
+fn hash_file(path: &Path, metadata: &fs::Metadata) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    if metadata.is_symlink() {
+        hasher.update(fs::read_link(path)?.as_os_str().as_encoded_bytes());
+    } else {
+        io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    }
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::time::Duration;
+
+    #[test]
+    fn matches_recorded_contents() {
+        let tmp = TempDir::new("baseline-matches");
+        let dir = tmp.path();
+        let path = dir.join("a.txt");
+        fs::write(&path, "vanilla").unwrap();
+        let file = VanillaFile::of(&path).unwrap();
+        assert_eq!(file.matches(&path).unwrap(), Some(true));
+
+        // touched but unchanged is hashed and still matches
+        let touch = |path: &Path| {
+            let modified = file.modified.unwrap() + Duration::from_secs(60);
+            fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+        };
+        touch(&path);
+        assert_eq!(file.matches(&path).unwrap(), Some(true));
+        fs::write(&path, "VANILLA").unwrap();
+        touch(&path);
+        assert_eq!(file.matches(&path).unwrap(), Some(false));
+        fs::write(&path, "updated vanilla").unwrap();
+        assert_eq!(file.matches(&path).unwrap(), Some(false));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(file.matches(&path).unwrap(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn records_symlinks_without_following_them() {
+        let tmp = TempDir::new("baseline-symlink");
+        let dir = tmp.path();
+        fs::create_dir(dir.join("target")).unwrap();
+        std::os::unix::fs::symlink("target", dir.join("link")).unwrap();
+        let file = VanillaFile::of(&dir.join("link")).unwrap();
+        assert_eq!(file.hash, <[u8; 32]>::from(Sha256::digest(b"target")));
+        let mut files = Vec::new();
+        walk_files(dir, "", &mut files).unwrap();
+        assert_eq!(files, ["/link"]);
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let tmp = TempDir::new("baseline-save");
+        let dir = tmp.path();
+        let path = dir.join("vanilla");
+        assert!(Baseline::load(&path).unwrap().is_none());
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        let baseline = Baseline {
+            files: BTreeMap::from([("/a.txt".to_string(), VanillaFile::of(&dir.join("a.txt")).unwrap())]),
+            dirs: BTreeSet::from(["/Saves".to_string()]),
+        };
+        baseline.save(&path).unwrap();
+        let loaded = Baseline::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.files, baseline.files);
+        assert_eq!(loaded.dirs, baseline.dirs);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::node::Overwrite;
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+/// Which active mods compete for the same files, see [`ModManager::conflicts`](crate::ModManager::conflicts).
+#[derive(Debug, Clone)]
+pub struct ConflictReport {
+    /// Every contested file, sorted by path.
+    pub files: Vec<FileConflict>,
+    /// Totals for every active mod involved in at least one conflict, in the order of the active mods.
+    pub mods: Vec<ModConflicts>,
+}
+
+/// A file provided by more than one active mod.
+#[derive(Debug, Clone)]
+pub struct FileConflict {
+    /// The path relative to the working directory, starting with a `/`.
+    pub path: String,
+    /// Every mod that provides the file, in the order of the active mods.
+    pub providers: Vec<Uuid>,
+    /// The mod whose file gets deployed.
+    pub winner: Uuid,
+    /// Whether some providers have a directory at `path` and others a file. The winner's replaces the
+    /// others' entirely, including every file in the directories.
+    pub type_mismatch: bool,
+}
+
+/// How one mod fares in the conflicts it is part of.
+#[derive(Debug, Clone)]
+pub struct ModConflicts {
+    pub uuid: Uuid,
+    /// Mods that lose files to this one, with how many files each loses.
+    pub overrides: Vec<(Uuid, usize)>,
+    /// Mods that win files from this one, with how many files each wins.
+    pub overridden_by: Vec<(Uuid, usize)>,
+}
+
+impl ConflictReport {
+    /// Build the report from the overwrites recorded while building the tree. `order` is the order of the
+    /// active mods.
+    pub(crate) fn new(order: &[Uuid], overwrites: Vec<Overwrite>) -> Self {
+        let position = |uuid: &Uuid| order.iter().position(|u| u == uuid).unwrap_or(usize::MAX);
+
+        // every overwrite of a path replaces the previous winner, so the chain holds all providers and ends
+        // with the winner; a dir replaced by a file is replaced once for every mod in it, and whatever was
+        // contested inside it before is gone along with it
+        let mut replaced_dirs: Vec<String> = Vec::new();
+        let mut kept = Vec::new();
+        for overwrite in overwrites.into_iter().rev() {
+            if replaced_dirs.iter().any(|dir| overwrite.path.starts_with(&format!("{}/", dir))) {
+                continue;
+            }
+            if overwrite.type_mismatch {
+                replaced_dirs.push(overwrite.path.clone());
+            }
+            kept.push(overwrite);
+        }
+        let mut contested: BTreeMap<String, (Vec<Uuid>, bool)> = BTreeMap::new();
+        for overwrite in kept.into_iter().rev() {
+            let (chain, type_mismatch) = contested.entry(overwrite.path).or_default();
+            if !chain.contains(&overwrite.replaced) {
+                chain.push(overwrite.replaced);
+            }
+            chain.retain(|&uuid| uuid != overwrite.by);
+            chain.push(overwrite.by);
+            *type_mismatch |= overwrite.type_mismatch;
+        }
+
+        let mut overrides: HashMap<Uuid, HashMap<Uuid, usize>> = HashMap::new();
+        let mut overridden_by: HashMap<Uuid, HashMap<Uuid, usize>> = HashMap::new();
+        let files = contested
+            .into_iter()
+            .map(|(path, (mut providers, type_mismatch))| {
+                let winner = *providers.last().unwrap();
+                providers.sort_by_key(position);
+                for &loser in providers.iter().filter(|&&p| p != winner) {
+                    *overrides.entry(winner).or_default().entry(loser).or_default() += 1;
+                    *overridden_by.entry(loser).or_default().entry(winner).or_default() += 1;
+                }
+                FileConflict {
+                    path,
+                    providers,
+                    winner,
+                    type_mismatch,
+                }
+            })
+            .collect();
+
+        let sorted = |counts: Option<HashMap<Uuid, usize>>| {
+            let mut counts: Vec<(Uuid, usize)> = counts.unwrap_or_default().into_iter().collect();
+            counts.sort_by_key(|(uuid, _)| position(uuid));
+            counts
+        };
+        let mods = order
+            .iter()
+            .map(|&uuid| ModConflicts {
+                uuid,
+                overrides: sorted(overrides.remove(&uuid)),
+                overridden_by: sorted(overridden_by.remove(&uuid)),
+            })
+            .filter(|summary| !summary.overrides.is_empty() || !summary.overridden_by.is_empty())
+            .collect();
+
+        Self { files, mods }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overwrite(path: &str, replaced: Uuid, by: Uuid, type_mismatch: bool) -> Overwrite {
+        Overwrite {
+            path: path.to_string(),
+            replaced,
+            by,
+            type_mismatch,
+        }
+    }
+
+    #[test]
+    fn reports_providers_in_order_and_the_winner() {
+        let [a, b, c, d] = [1, 2, 3, 4].map(Uuid::from_u128);
+        // mods are laid over each other from the last active one to the first
+        let overwrites = vec![
+            overwrite("/x", c, b, false),
+            overwrite("/y", c, a, false),
+            overwrite("/x", b, a, false),
+        ];
+        let report = ConflictReport::new(&[a, b, c, d], overwrites);
+        let files: Vec<_> = report
+            .files
+            .iter()
+            .map(|file| (file.path.as_str(), file.providers.clone(), file.winner, file.type_mismatch))
+            .collect();
+        assert_eq!(files, [("/x", vec![a, b, c], a, false), ("/y", vec![a, c], a, false)]);
+    }
+
+    #[test]
+    fn counts_files_won_and_lost_by_each_mod() {
+        let [a, b, c, d] = [1, 2, 3, 4].map(Uuid::from_u128);
+        let overwrites = vec![
+            overwrite("/x", c, b, false),
+            overwrite("/y", c, a, false),
+            overwrite("/x", b, a, false),
+            overwrite("/z", c, b, false),
+        ];
+        let report = ConflictReport::new(&[a, b, c, d], overwrites);
+        let mods: Vec<_> = report
+            .mods
+            .iter()
+            .map(|summary| (summary.uuid, summary.overrides.clone(), summary.overridden_by.clone()))
+            .collect();
+        // only the winner of a file takes it from the others, and mods without conflicts are left out
+        assert_eq!(
+            mods,
+            [
+                (a, vec![(b, 1), (c, 2)], vec![]),
+                (b, vec![(c, 1)], vec![(a, 1)]),
+                (c, vec![], vec![(a, 2), (b, 1)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn dir_replaced_by_a_file_counts_every_mod_in_it_once() {
+        let [a, b, c] = [1, 2, 3].map(Uuid::from_u128);
+        // c and b both have files in the dir /d, which a replaces with a file
+        let overwrites = vec![
+            overwrite("/d/x", c, b, false),
+            overwrite("/d", b, a, true),
+            overwrite("/d", c, a, true),
+        ];
+        let report = ConflictReport::new(&[a, b, c], overwrites);
+        let file = &report.files[0];
+        assert_eq!((file.path.as_str(), file.providers.as_slice(), file.winner), ("/d", [a, b, c].as_slice(), a));
+        assert!(file.type_mismatch);
+        // the file b won inside the dir is gone with it
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(report.mods[0].overrides, [(b, 1), (c, 1)]);
+        assert_eq!(report.mods[1].overridden_by, [(a, 1)]);
+    }
+}
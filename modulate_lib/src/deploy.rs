@@ -0,0 +1,271 @@
+use crate::ModError;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+/// How mod files are put into the working directory.
+///
+/// If a strategy isn't possible for a file, because the mod and the working directory are on different
+/// filesystems or the filesystem doesn't allow it, the next one in its [fallback chain](DeployStrategy::fallback)
+/// is tried.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DeployStrategy {
+    /// A hard link to the mod file. Free and instant, but only within one filesystem.
+    #[default]
+    HardLink,
+    /// A symbolic link with a path relative to the deployed file, so the game and the mods can be moved
+    /// together.
+    RelativeSymlink,
+    /// A symbolic link with an absolute path to the mod file.
+    AbsoluteSymlink,
+    /// A full copy of the mod file.
+    Copy,
+    /// A copy-on-write clone of the mod file (`FICLONE`), on filesystems that support it. Linux only.
+    Reflink,
+}
+
+impl DeployStrategy {
+    /// The strategy to try when this one isn't possible: hard links and reflinks fall back to a full copy,
+    /// hard links trying a reflink first. Symlinks fall back to a copy as well.
+    pub fn fallback(self) -> Option<Self> {
+        match self {
+            DeployStrategy::HardLink => Some(DeployStrategy::Reflink),
+            DeployStrategy::Reflink => Some(DeployStrategy::Copy),
+            DeployStrategy::RelativeSymlink | DeployStrategy::AbsoluteSymlink => Some(DeployStrategy::Copy),
+            DeployStrategy::Copy => None,
+        }
+    }
+
+    /// Create `target` from `mod_file` with this strategy or, if that isn't possible, the first of its
+    /// fallbacks that is. Returns the strategy that was used.
+    pub(crate) fn deploy(self, mod_file: &Path, target: &Path) -> io::Result<Self> {
+        let mut strategy = self;
+        loop {
+            match strategy.deploy_once(mod_file, target) {
+                Ok(()) => return Ok(strategy),
+                Err(e) if strategy.can_fall_back(&e) => {
+                    let Some(next) = strategy.fallback() else {
+                        return Err(e);
+                    };
+                    info!(" - Can't deploy with {} ({}), trying {}", strategy, e, next);
+                    strategy = next;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn deploy_once(self, mod_file: &Path, target: &Path) -> io::Result<()> {
+        match self {
+            DeployStrategy::HardLink => fs::hard_link(mod_file, target),
+            DeployStrategy::RelativeSymlink => {
+                let parent = target.parent().unwrap_or(Path::new(""));
+                symlink(&relative_path(parent, mod_file), target)
+            }
+            DeployStrategy::AbsoluteSymlink => symlink(mod_file, target),
+            DeployStrategy::Copy | DeployStrategy::Reflink => {
+                // copies are made next to the target and renamed into place, so that one cut short by a crash
+                // is never mistaken for a game file and backed up
+                let mut name = target.file_name().unwrap_or_default().to_os_string();
+                name.push(".modulate-tmp");
+                let tmp = target.with_file_name(name);
+                let _ = fs::remove_file(&tmp);
+                let copied = match self {
+                    DeployStrategy::Copy => fs::copy(mod_file, &tmp).map(|_| ()),
+                    _ => reflink(mod_file, &tmp),
+                };
+                copied.and_then(|()| fs::rename(&tmp, target)).inspect_err(|_| {
+                    let _ = fs::remove_file(&tmp);
+                })
+            }
+        }
+    }
+
+    /// Whether `e` means this strategy can't be used here at all, rather than that something went wrong.
+    fn can_fall_back(self, e: &io::Error) -> bool {
+        if e.kind() == io::ErrorKind::CrossesDevices || e.kind() == io::ErrorKind::Unsupported {
+            return true;
+        }
+        #[cfg(unix)]
+        {
+            let code = e.raw_os_error();
+            // filesystems without reflink support answer FICLONE with EOPNOTSUPP or EINVAL
+            code == Some(libc::EPERM)
+                || (self == DeployStrategy::Reflink && (code == Some(libc::EOPNOTSUPP) || code == Some(libc::EINVAL)))
+        }
+        #[cfg(not(unix))]
+        {
+            e.kind() == io::ErrorKind::PermissionDenied
+        }
+    }
+}
+
+impl fmt::Display for DeployStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DeployStrategy::HardLink => "hardlink",
+            DeployStrategy::RelativeSymlink => "symlink",
+            DeployStrategy::AbsoluteSymlink => "absolute-symlink",
+            DeployStrategy::Copy => "copy",
+            DeployStrategy::Reflink => "reflink",
+        })
+    }
+}
+
+impl FromStr for DeployStrategy {
+    type Err = ModError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hardlink" => Ok(DeployStrategy::HardLink),
+            "symlink" => Ok(DeployStrategy::RelativeSymlink),
+            "absolute-symlink" => Ok(DeployStrategy::AbsoluteSymlink),
+            "copy" => Ok(DeployStrategy::Copy),
+            "reflink" => Ok(DeployStrategy::Reflink),
+            _ => Err(ModError::InvalidDeployStrategy(s.to_string())),
+        }
+    }
+}
+
+/// Whether `target` is `mod_file` as deployed by any strategy: a hard link to it, a symlink to it or, if
+/// `compare_contents` is set, a file with the same contents.
+///
+/// Contents are only compared when recovering, to recognize copies made before an interruption. A game
+/// file that happens to be identical to a mod file must not be mistaken for a deployed one, or it would
+/// never get backed up.
+pub(crate) fn is_deployed(target: &Path, mod_file: &Path, compare_contents: bool) -> bool {
+    let Ok(metadata) = fs::symlink_metadata(target) else {
+        return false;
+    };
+    if metadata.is_symlink() {
+        return matches!(
+            (fs::canonicalize(target), fs::canonicalize(mod_file)),
+            (Ok(a), Ok(b)) if a == b
+        );
+    }
+    crate::same_file(target, mod_file) || (compare_contents && same_contents(target, mod_file).unwrap_or(false))
+}
+
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    use io::Read;
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    let (mut a, mut b) = (io::BufReader::new(fs::File::open(a)?), io::BufReader::new(fs::File::open(b)?));
+    let (mut buf_a, mut buf_b) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        let read = a.read(&mut buf_a)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..read])?;
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+/// The path that leads from the directory `from` to `to`. Both must be absolute.
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    path.extend(&to[common..]);
+    path
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(mod_file: &Path, target: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let source = fs::File::open(mod_file)?;
+    let dest = fs::OpenOptions::new().write(true).create_new(true).open(target)?;
+    // SAFETY: both descriptors are open for the duration of the call
+    if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == 0 {
+        return dest.set_permissions(source.metadata()?.permissions());
+    }
+    Err(io::Error::last_os_error())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_mod_file: &Path, _target: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn chain(strategy: DeployStrategy) -> Vec<DeployStrategy> {
+        std::iter::successors(Some(strategy), |strategy| strategy.fallback()).collect()
+    }
+
+    #[test]
+    fn falls_back_to_a_copy() {
+        use DeployStrategy::*;
+        assert_eq!(chain(HardLink), [HardLink, Reflink, Copy]);
+        assert_eq!(chain(Reflink), [Reflink, Copy]);
+        assert_eq!(chain(RelativeSymlink), [RelativeSymlink, Copy]);
+        assert_eq!(chain(AbsoluteSymlink), [AbsoluteSymlink, Copy]);
+        assert_eq!(chain(Copy), [Copy]);
+    }
+
+    #[test]
+    fn parses_what_it_displays() {
+        use DeployStrategy::*;
+        for strategy in [HardLink, RelativeSymlink, AbsoluteSymlink, Copy, Reflink] {
+            assert_eq!(strategy.to_string().parse::<DeployStrategy>().unwrap(), strategy);
+        }
+        assert!("hard-link".parse::<DeployStrategy>().is_err());
+    }
+
+    #[test]
+    fn relative_paths() {
+        let relative = |from: &str, to: &str| relative_path(Path::new(from), Path::new(to));
+        // siblings
+        assert_eq!(relative("/game/data", "/game/mods/a.txt"), Path::new("../mods/a.txt"));
+        assert_eq!(relative("/game", "/mods/x/a.txt"), Path::new("../mods/x/a.txt"));
+        // nested
+        assert_eq!(relative("/game", "/game/data/a.txt"), Path::new("data/a.txt"));
+        assert_eq!(relative("/game/data", "/game/data/a.txt"), Path::new("a.txt"));
+        // up and back down, sharing only part of a name
+        assert_eq!(relative("/game/data/deep", "/game/a.txt"), Path::new("../../a.txt"));
+        assert_eq!(relative("/game/data", "/game/data2/a.txt"), Path::new("../data2/a.txt"));
+        assert_eq!(relative("/a/b/c", "/d/e.txt"), Path::new("../../../d/e.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn relative_symlinks_lead_to_the_mod_file() {
+        let tmp = TempDir::new("deploy-relative");
+        let root = tmp.path();
+        fs::create_dir_all(root.join("mods/mod")).unwrap();
+        fs::create_dir_all(root.join("game/data")).unwrap();
+        let mod_file = root.join("mods/mod/a.txt");
+        fs::write(&mod_file, "a").unwrap();
+        let target = root.join("game/data/a.txt");
+        let used = DeployStrategy::RelativeSymlink.deploy(&mod_file, &target).unwrap();
+        assert_eq!(used, DeployStrategy::RelativeSymlink);
+        assert_eq!(fs::read_link(&target).unwrap(), Path::new("../../mods/mod/a.txt"));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "a");
+        assert!(is_deployed(&target, &mod_file, false));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::r#mod::ModMetadata;
+use crate::ModError;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+/// Which files of a mod get deployed, from the `include` and `exclude` globs in its `mod.toml` and the
+/// ignore rules the user added to it.
+///
+/// Globs match paths relative to the mod directory, with `/` separators. A glob without a `/` matches
+/// names at any depth, so `*.md` excludes every readme and `.git` every git folder. A dir that is
+/// excluded is skipped with everything in it, and a dir that is included includes everything in it.
+#[derive(Debug)]
+pub(crate) struct PathFilter {
+    /// `None` if every file is included.
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    /// Paths that are deployed somewhere else than where they are in the mod, if at all: the dirs of the
+    /// mod's option choices and the sources of its mappings.
+    remapped: Vec<String>,
+    /// Config fragments, merged into the file they change instead of replacing it.
+    merge: GlobSet,
+    /// Patches, applied to the file they change.
+    patches: GlobSet,
+}
+
+impl PathFilter {
+    pub(crate) fn new(metadata: &ModMetadata, ignore: &[String]) -> Result<Self, ModError> {
+        let include = if metadata.include.is_empty() {
+            None
+        } else {
+            Some(glob_set(&metadata.include)?)
+        };
+        let exclude = glob_set(metadata.exclude.iter().chain(ignore))?;
+        let choice_dirs = metadata.options.iter().flat_map(|group| &group.choices).map(|choice| choice.dir());
+        let mapped = metadata.mappings.iter().map(|mapping| mapping.from());
+        let remapped = choice_dirs.chain(mapped).map(str::to_string).collect();
+        Ok(Self {
+            include,
+            exclude,
+            remapped,
+            merge: glob_set(&metadata.merge)?,
+            patches: glob_set(&metadata.patches)?,
+        })
+    }
+
+    /// Whether `path`, relative to the mod directory, is left out along with everything in it.
+    pub(crate) fn excluded(&self, path: &str) -> bool {
+        self.exclude.is_match(path)
+    }
+
+    /// Whether `path` or one of the dirs it is in is left out.
+    pub(crate) fn excluded_with_parents(&self, path: &str) -> bool {
+        let mut parent = path;
+        loop {
+            if !parent.is_empty() && self.excluded(parent) {
+                return true;
+            }
+            match parent.rsplit_once('/') {
+                Some((next, _)) => parent = next,
+                None => return false,
+            }
+        }
+    }
+
+    /// Whether `path` is left out where it is, because it is deployed somewhere else.
+    pub(crate) fn remapped(&self, path: &str) -> bool {
+        self.remapped.iter().any(|remapped| remapped == path)
+    }
+
+    /// Whether `path` is included, if nothing it is in already was. The mod root is `""`.
+    pub(crate) fn included(&self, path: &str) -> bool {
+        self.include.as_ref().is_none_or(|include| !path.is_empty() && include.is_match(path))
+    }
+
+    /// Whether the file at `path` is a config fragment.
+    pub(crate) fn merged(&self, path: &str) -> bool {
+        self.merge.is_match(path)
+    }
+
+    /// Whether the file at `path` is a patch.
+    pub(crate) fn patched(&self, path: &str) -> bool {
+        self.patches.is_match(path)
+    }
+
+    /// Whether `path` or one of the dirs it is in is included.
+    pub(crate) fn included_with_parents(&self, path: &str) -> bool {
+        let mut parent = path;
+        loop {
+            if self.included(parent) {
+                return true;
+            }
+            match parent.rsplit_once('/') {
+                Some((next, _)) => parent = next,
+                None => return self.included(""),
+            }
+        }
+    }
+}
+
+/// Check that `patterns` are valid globs, e.g. before keeping them as ignore rules.
+pub(crate) fn validate(patterns: &[String]) -> Result<(), ModError> {
+    glob_set(patterns).map(|_| ())
+}
+
+fn glob_set<'a>(patterns: impl IntoIterator<Item = &'a String>) -> Result<GlobSet, ModError> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = |pattern: &str| {
+            Glob::new(pattern).map_err(|source| ModError::InvalidGlob {
+                pattern: pattern.to_string(),
+                source,
+            })
+        };
+        let trimmed = pattern.trim_matches('/');
+        builder.add(glob(trimmed)?);
+        if !trimmed.contains('/') {
+            builder.add(glob(&format!("**/{}", trimmed))?);
+        }
+    }
+    builder.build().map_err(|source| ModError::InvalidGlob {
+        pattern: String::new(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(metadata: &str, ignore: &[&str]) -> PathFilter {
+        let toml = format!("name = \"test\"\nversion = \"1.0.0\"\nuuid = \"{}\"\n{}", uuid::Uuid::nil(), metadata);
+        let ignore: Vec<String> = ignore.iter().map(|rule| rule.to_string()).collect();
+        PathFilter::new(&toml::from_str(&toml).unwrap(), &ignore).unwrap()
+    }
+
+    #[test]
+    fn excludes_everything_in_an_excluded_dir() {
+        let filter = filter("exclude = [\"extras\", \"docs/*.png\"]", &["opt/"]);
+        assert!(filter.excluded_with_parents("extras"));
+        assert!(filter.excluded_with_parents("extras/readme.txt"));
+        assert!(filter.excluded_with_parents("data/extras/deep/file.txt"));
+        assert!(filter.excluded_with_parents("opt/settings.ini"));
+        assert!(filter.excluded_with_parents("docs/shot.png"));
+        assert!(!filter.excluded_with_parents("docs/readme.txt"));
+        assert!(!filter.excluded_with_parents("extras2/file.txt"));
+        assert!(!filter.excluded_with_parents(""));
+    }
+}
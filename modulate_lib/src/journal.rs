@@ -0,0 +1,210 @@
+use crate::node::{Operation, SourcedNode};
+use crate::{IoResultExt, ModError};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// What a deploy is about to do: the operations, in order, and the tree they lead to.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Journal {
+    pub(crate) new_tree: SourcedNode,
+    pub(crate) ops: Vec<Operation>,
+}
+
+/// Progress markers appended to the journal after its header, one per finished step.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Entry {
+    Applied(u64),
+    RolledBack(u64),
+}
+
+/// An open journal file.
+///
+/// The header is synced to disk before the first operation runs, so a journal that exists always
+/// describes the whole deploy. Progress entries are appended without syncing: they survive the process
+/// being killed, which is what the journal is for, at the cost of one small write per operation.
+pub(crate) struct JournalFile {
+    file: fs::File,
+    path: PathBuf,
+}
+
+impl JournalFile {
+    pub(crate) fn create(path: &Path, journal: &Journal) -> Result<Self, ModError> {
+        let mut file = fs::File::create(path).at(path)?;
+        let header = bincode::serialize(journal).map_err(|source| ModError::JournalFailed {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        file.write_all(&(header.len() as u64).to_le_bytes()).at(path)?;
+        file.write_all(&header).at(path)?;
+        file.sync_all().at(path)?;
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Open the journal left behind by a deploy that didn't finish, along with the progress it recorded.
+    ///
+    /// A journal whose header is incomplete is discarded, since no operation runs before the header is on
+    /// disk. A trailing, partially written entry is cut off so new entries can be appended after it.
+    pub(crate) fn open(path: &Path) -> Result<Option<(Journal, Vec<Entry>, Self)>, ModError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let bytes = fs::read(path).at(path)?;
+        let header_len = bytes
+            .get(..8)
+            .map(|len| u64::from_le_bytes(len.try_into().unwrap()) as usize)
+            .unwrap_or(usize::MAX);
+        let journal = match bytes
+            .get(8..8usize.saturating_add(header_len))
+            .map(bincode::deserialize::<Journal>)
+        {
+            Some(Ok(journal)) => journal,
+            _ => {
+                warn!("Discarding incomplete deploy journal {}", path.display());
+                fs::remove_file(path).at(path)?;
+                return Ok(None);
+            }
+        };
+        let mut rest = &bytes[8 + header_len..];
+        let mut entries = Vec::new();
+        loop {
+            let mut cursor = rest;
+            match bincode::deserialize_from::<_, Entry>(&mut cursor) {
+                Ok(entry) => {
+                    entries.push(entry);
+                    rest = cursor;
+                }
+                Err(_) => break,
+            }
+        }
+        let file = fs::OpenOptions::new().append(true).open(path).at(path)?;
+        file.set_len((bytes.len() - rest.len()) as u64).at(path)?;
+        Ok(Some((
+            journal,
+            entries,
+            Self {
+                file,
+                path: path.to_path_buf(),
+            },
+        )))
+    }
+
+    fn append(&mut self, entry: Entry) -> Result<(), ModError> {
+        let bytes = bincode::serialize(&entry).map_err(|source| ModError::JournalFailed {
+            path: self.path.clone(),
+            source,
+        })?;
+        self.file.write_all(&bytes).at(&self.path)
+    }
+
+    pub(crate) fn applied(&mut self, index: usize) -> Result<(), ModError> {
+        self.append(Entry::Applied(index as u64))
+    }
+
+    pub(crate) fn rolled_back(&mut self, index: usize) -> Result<(), ModError> {
+        self.append(Entry::RolledBack(index as u64))
+    }
+
+    /// Delete the journal once the deploy it describes is fully applied or fully rolled back.
+    pub(crate) fn remove(self) -> Result<(), ModError> {
+        drop(self.file);
+        fs::remove_file(&self.path).at(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::OperationKind;
+    use crate::test_util::TempDir;
+    use uuid::Uuid;
+
+    fn journal() -> Journal {
+        let uuid = Uuid::from_u128(1);
+        Journal {
+            new_tree: SourcedNode::Dir {
+                name: String::new(),
+                children: [(
+                    "a".to_string(),
+                    SourcedNode::File {
+                        name: "a".to_string(),
+                        source: uuid,
+                        origin: None,
+                    },
+                )]
+                .into(),
+            },
+            ops: vec![
+                Operation {
+                    kind: OperationKind::CreateFile(uuid),
+                    path: "/a".to_string(),
+                },
+                Operation {
+                    kind: OperationKind::CreateDir,
+                    path: "/b".to_string(),
+                },
+            ],
+        }
+    }
+
+    /// Write a journal with two applied entries and return its bytes and the length of its header.
+    fn write_journal(path: &Path) -> (Vec<u8>, usize) {
+        let mut file = JournalFile::create(path, &journal()).unwrap();
+        let header_len = fs::metadata(path).unwrap().len() as usize;
+        file.applied(0).unwrap();
+        file.applied(1).unwrap();
+        drop(file);
+        (fs::read(path).unwrap(), header_len)
+    }
+
+    #[test]
+    fn reopens_complete_journal() {
+        let tmp = TempDir::new("journal-complete");
+        let path = tmp.path().join("journal");
+        write_journal(&path);
+        let (journal, entries, file) = JournalFile::open(&path).unwrap().unwrap();
+        assert_eq!(journal.ops.len(), 2);
+        assert!(matches!(entries[..], [Entry::Applied(0), Entry::Applied(1)]));
+        file.remove().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn discards_truncated_header() {
+        let tmp = TempDir::new("journal-header");
+        let path = tmp.path().join("journal");
+        let (bytes, header_len) = write_journal(&path);
+        for len in [0, 3, 8, 9, header_len - 1] {
+            fs::write(&path, &bytes[..len]).unwrap();
+            assert!(JournalFile::open(&path).unwrap().is_none(), "header cut at {}", len);
+            assert!(!path.exists());
+        }
+    }
+
+    #[test]
+    fn cuts_off_partial_entry() {
+        let tmp = TempDir::new("journal-entry");
+        let path = tmp.path().join("journal");
+        let (bytes, header_len) = write_journal(&path);
+        let entry_len = (bytes.len() - header_len) / 2;
+        for len in header_len..bytes.len() {
+            fs::write(&path, &bytes[..len]).unwrap();
+            let (_, entries, mut file) = JournalFile::open(&path).unwrap().unwrap();
+            let complete = (len - header_len) / entry_len;
+            assert_eq!(entries.len(), complete, "journal cut at {}", len);
+            assert_eq!(fs::metadata(&path).unwrap().len() as usize, header_len + complete * entry_len);
+
+            // new entries go right after the last complete one
+            file.rolled_back(1).unwrap();
+            let (_, entries, file) = JournalFile::open(&path).unwrap().unwrap();
+            assert_eq!(entries.len(), complete + 1);
+            assert!(matches!(entries.last(), Some(Entry::RolledBack(1))));
+            drop(file);
+        }
+    }
+}
@@ -1,13 +1,45 @@
+mod archive;
+mod baseline;
+mod conflicts;
+mod deploy;
+mod filter;
+mod journal;
+mod merge;
 pub mod r#mod;
 mod node;
+mod order;
+mod patch;
+mod plan;
+mod state;
+mod verify;
 
-use crate::node::{Operation, OperationKind, SourcedNode};
-use crate::r#mod::{Mod, ModMetadata};
-use log::{error, info, trace};
+#[cfg(test)]
+mod test_util;
+
+pub use crate::baseline::{VanillaChange, VanillaChangeKind};
+pub use crate::conflicts::{ConflictReport, FileConflict, ModConflicts};
+pub use crate::deploy::DeployStrategy;
+pub use crate::node::{Operation, OperationKind};
+pub use crate::order::LoadRule;
+pub use crate::plan::{DeployPlan, PlannedFile, PlannedOperation, RejectedHunk};
+pub use crate::verify::{ChangeKind, ChangedFile, Resolution};
+
+use crate::baseline::{walk, walk_files, Baseline, VanillaFile};
+use crate::journal::{Entry, Journal, JournalFile};
+use crate::merge::{ConfigFormat, GeneratedFile, TextEncoding};
+use crate::node::{Node, Overwrite, SourcedNode};
+use crate::order::stable_topological_sort;
+use crate::patch::Patch;
+use crate::r#mod::{Fragment, FragmentKind, Mod, ModMetadata, ModSettings};
+use crate::state::{State, StoredMod};
+use crate::verify::FileStamp;
+use log::{error, info, trace, warn};
+use semver::{Version, VersionReq};
 use slotmap::{new_key_type, SlotMap};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 use uuid::Uuid;
 
@@ -19,36 +51,224 @@ pub enum ModError {
     InvalidModUuid(Uuid),
     #[error("Invalid mod order: {0:?}")]
     InvalidModOrder(Vec<usize>),
+    #[error("Mod is already registered: {0}")]
+    ModAlreadyAdded(Uuid),
+    #[error("Built-in mods can't be activated, deactivated, removed or changed: {0}")]
+    BuiltInMod(Uuid),
+    #[error("Mod is not active: {0}")]
+    ModNotActive(Uuid),
+    #[error("Invalid mod order: unknown {unknown:?}, inactive {inactive:?}, missing {missing:?}, duplicated {duplicated:?}")]
+    InvalidModUuidOrder {
+        unknown: Vec<Uuid>,
+        inactive: Vec<Uuid>,
+        missing: Vec<Uuid>,
+        duplicated: Vec<Uuid>,
+    },
     #[error("Mod metadata missing: {0}")]
     ModMetadataMissing(String),
     #[error("Invalid mod metadata: {0}")]
     InvalidModMetadata(String),
     #[error("Couldn't create bak dir: {0}")]
     BakDirCreationFailed(String),
+    #[error("Couldn't load manager state: {0}")]
+    StateLoadFailed(String),
+    #[error("Couldn't save manager state: {0}")]
+    StateSaveFailed(String),
+    #[error("IO error on {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Invalid file name: {}", .0.display())]
+    InvalidFileName(PathBuf),
+    #[error("Couldn't read or write mod cache {}: {source}", path.display())]
+    ModCacheFailed {
+        path: PathBuf,
+        #[source]
+        source: bincode::Error,
+    },
+    #[error("Operation {operation} failed on {}: {source}", path.display())]
+    OperationFailed {
+        operation: Operation,
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Couldn't write deploy journal {}: {source}", path.display())]
+    JournalFailed {
+        path: PathBuf,
+        #[source]
+        source: bincode::Error,
+    },
+    #[error("Mod {name} requires mod {required} {version}, which is not active")]
+    MissingDependency {
+        uuid: Uuid,
+        name: String,
+        required: Uuid,
+        version: VersionReq,
+    },
+    #[error("Mod {name} requires mod {required} {version}, but version {found} is active")]
+    DependencyVersionMismatch {
+        uuid: Uuid,
+        name: String,
+        required: Uuid,
+        version: VersionReq,
+        found: Version,
+    },
+    #[error("Mod {name} is incompatible with mod {other_name} ({other}) {version}")]
+    IncompatibleMods {
+        uuid: Uuid,
+        name: String,
+        other: Uuid,
+        other_name: String,
+        version: VersionReq,
+    },
+    #[error("Unsupported archive format: {}", .0.display())]
+    UnsupportedArchive(PathBuf),
+    #[error("Couldn't unpack archive {}: {source}", path.display())]
+    ArchiveFailed {
+        path: PathBuf,
+        #[source]
+        source: zip::result::ZipError,
+    },
+    #[error("Load order rules form a cycle, each mod has to load after the next: {}", names.join(" -> "))]
+    LoadOrderCycle { chain: Vec<Uuid>, names: Vec<String> },
+    #[error("Deployed files were changed outside the manager: {}", changed_paths(.0))]
+    DeployedFilesChanged(Vec<ChangedFile>),
+    #[error("Backups could not be restored, files are in the way: {}", .0.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", "))]
+    BackupsLeftBehind(Vec<PathBuf>),
+    #[error("Backups are outdated, the game may have been updated: {}", .0.join(", "))]
+    StaleBackups(Vec<String>),
+    #[error("The game may have been updated, new files could be game files: {}", .0.join(", "))]
+    GameUpdated(Vec<String>),
+    #[error("No file is deployed at {0}")]
+    NotDeployed(String),
+    #[error("The mod file deployed at {0} was edited in place, its original is gone")]
+    OriginalLost(String),
+    #[error("Invalid glob {pattern:?}: {source}")]
+    InvalidGlob {
+        pattern: String,
+        #[source]
+        source: globset::Error,
+    },
+    #[error("Couldn't merge config {}: {reason}", path.display())]
+    MergeFailed { path: PathBuf, reason: String },
+    #[error("Invalid patch {}: {reason}", path.display())]
+    InvalidPatch { path: PathBuf, reason: String },
+    #[error("Mod {uuid} couldn't be loaded from {}: {reason}", dir.display())]
+    ModNotLoaded { uuid: Uuid, dir: PathBuf, reason: String },
+    #[error("Invalid option choice: {0}")]
+    InvalidChoice(String),
+    #[error("Unknown deploy strategy: {0}")]
+    InvalidDeployStrategy(String),
+    #[error("The deploy plan is stale, something else was deployed since it was made")]
+    StalePlan,
+    #[error("A previous deploy was interrupted")]
+    DeployInterrupted,
+    /// The deploy failed and every operation it had started, listed in the order they were undone, was
+    /// rolled back. The working directory is as it was before the deploy.
+    #[error("Deploy failed, rolled back {} operations: {source}", rolled_back.len())]
+    DeployRolledBack {
+        #[source]
+        source: Box<ModError>,
+        rolled_back: Vec<Operation>,
+    },
+    /// The deploy failed and so did undoing it. `rolled_back` were undone, `not_rolled_back` are still
+    /// applied. The journal is kept so the rollback can be retried.
+    #[error("Deploy failed ({source}) and rolling it back failed too: {rollback_error}")]
+    RollbackFailed {
+        #[source]
+        source: Box<ModError>,
+        rollback_error: Box<ModError>,
+        rolled_back: Vec<Operation>,
+        not_rolled_back: Vec<Operation>,
+    },
+}
+
+/// Attach the failing path (and operation, while deploying) to an [`io::Error`].
+pub(crate) trait IoResultExt<T> {
+    fn at(self, path: &Path) -> Result<T, ModError>;
+    fn during(self, operation: &Operation, path: &Path) -> Result<T, ModError>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn at(self, path: &Path) -> Result<T, ModError> {
+        self.map_err(|source| ModError::Io {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    fn during(self, operation: &Operation, path: &Path) -> Result<T, ModError> {
+        self.map_err(|source| ModError::OperationFailed {
+            operation: operation.clone(),
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+/// What [`ModManager::new`] did about a deploy that a previous process didn't get to finish.
+#[derive(Debug)]
+pub enum Recovery {
+    /// The deploy was carried out to the end; these operations were applied during recovery.
+    Finished(Vec<Operation>),
+    /// The deploy was undone; these operations were rolled back, in that order.
+    RolledBack(Vec<Operation>),
 }
 
 new_key_type! {
     pub struct ModKey;
 }
 
+/// The uuid of the built-in overwrite mod, see [`ModManager::overwrite_mod`].
+const OVERWRITE_MOD: Uuid = Uuid::from_u128(0x6f766572_7772_4974_a500_000000000000);
+
+/// The uuid of the built-in mod that deploys the files generated at deploy time, such as merged configs.
+/// It loads after every other mod, the overwrite mod included.
+const GENERATED_MOD: Uuid = Uuid::from_u128(0x67656e65_7261_4465_a400_000000000000);
+
 #[derive(Debug)]
 pub struct ModManager {
     working_dir: PathBuf,
     bak_dir: PathBuf,
+    state_path: PathBuf,
+    journal_path: PathBuf,
+    library_dir: PathBuf,
+    cache_dir: PathBuf,
+    baseline_path: PathBuf,
+    baseline: Baseline,
     active_mods: Vec<ModKey>,
     inactive_mods: Vec<ModKey>,
     hash_map: HashMap<Uuid, ModKey>,
     current_active_tree: SourcedNode,
     slotmap: SlotMap<ModKey, Mod>,
+    load_rules: Vec<LoadRule>,
+    deploy_strategy: DeployStrategy,
+    mod_strategies: HashMap<Uuid, DeployStrategy>,
+    case_insensitive: bool,
+    deployed_files: HashMap<String, FileStamp>,
+    recovery: Option<Recovery>,
 }
 
 impl ModManager {
     /// Create a new ModManager with the given working directory.
     ///
+    /// If a previous manager saved its state next to `bak_dir`, the registered mods, their order and
+    /// the last deployed tree are restored from it. Scans of the mod directories are cached next to
+    /// `bak_dir` as well, never inside the mods themselves.
+    ///
+    /// The first time a working directory is managed, the size and hash of every file in it is recorded,
+    /// which can take a while for a large game. See [`ModManager::vanilla_changes`].
+    ///
+    /// If a previous deploy was interrupted, it is finished, or rolled back if it had already started
+    /// rolling back or can't be finished, before the manager is returned. See [`ModManager::recovery`].
+    ///
     /// # Examples
-    /// ```
+    /// ```no_run
     /// use modulate_lib::ModManager;
-    /// let manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap());
+    /// let manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
     /// ```
     pub fn new(working_dir: PathBuf, bak_dir: PathBuf) -> Result<Self, ModError> {
         // check if working_dir exists
@@ -59,11 +279,26 @@ impl ModManager {
             error!("Failed to create backup directory");
             ModError::BakDirCreationFailed(e.to_string())
         })?;
-        let working_dir = working_dir.canonicalize().unwrap();
-        let bak_dir = bak_dir.canonicalize().unwrap();
-        Ok(Self {
+        let working_dir = working_dir.canonicalize().at(&working_dir)?;
+        let bak_dir = bak_dir.canonicalize().at(&bak_dir)?;
+        let state_path = sibling_path(&bak_dir, "state");
+        let journal_path = sibling_path(&bak_dir, "journal");
+        let library_dir = sibling_path(&bak_dir, "mods");
+        let cache_dir = sibling_path(&bak_dir, "cache");
+        fs::create_dir_all(&cache_dir).at(&cache_dir)?;
+        let baseline_path = sibling_path(&bak_dir, "vanilla");
+        let overwrite_dir = sibling_path(&bak_dir, "overwrite");
+        let generated_dir = sibling_path(&bak_dir, "generated");
+        fs::create_dir_all(&generated_dir).at(&generated_dir)?;
+        let mut manager = Self {
             working_dir,
             bak_dir,
+            state_path,
+            journal_path,
+            library_dir,
+            cache_dir,
+            baseline_path,
+            baseline: Baseline::default(),
             active_mods: Vec::new(),
             inactive_mods: Vec::new(),
             hash_map: HashMap::new(),
@@ -72,18 +307,197 @@ impl ModManager {
                 children: HashMap::new(),
             },
             slotmap: SlotMap::with_key(),
-        })
+            load_rules: Vec::new(),
+            deploy_strategy: DeployStrategy::default(),
+            mod_strategies: HashMap::new(),
+            case_insensitive: false,
+            deployed_files: HashMap::new(),
+            recovery: None,
+        };
+        let state = State::load(&manager.state_path)?;
+        let overwrite_settings = state.as_ref().map(|state| state.overwrite_mod.settings.clone()).unwrap_or_default();
+        manager.load_overwrite_mod(overwrite_dir, overwrite_settings)?;
+        let key = manager.slotmap.insert(Mod::built_in("Generated", GENERATED_MOD, generated_dir));
+        manager.hash_map.insert(GENERATED_MOD, key);
+        if let Some(state) = state {
+            manager.restore_state(state);
+        }
+        manager.set_generated_files(manager.current_active_tree.node_of(GENERATED_MOD));
+        // loaded before recovering, so that rolling back keeps the vanilla dirs
+        let baseline = Baseline::load(&manager.baseline_path)?;
+        let recorded = baseline.is_some();
+        manager.baseline = baseline.unwrap_or_default();
+        manager.recovery = manager.recover()?;
+        if !recorded {
+            manager.record_baseline()?;
+        }
+        Ok(manager)
+    }
+
+    /// Load the built-in overwrite mod from `dir`, creating it if this is the first run.
+    fn load_overwrite_mod(&mut self, dir: PathBuf, settings: ModSettings) -> Result<(), ModError> {
+        let metadata_path = dir.join("mod.toml");
+        if !metadata_path.exists() {
+            fs::create_dir_all(&dir).at(&dir)?;
+            let metadata = format!("name = \"Overwrite\"\nversion = \"0.0.0\"\nuuid = \"{}\"\n", OVERWRITE_MOD);
+            fs::write(&metadata_path, metadata).at(&metadata_path)?;
+        }
+        let key = self.slotmap.insert(Mod::new(dir, &self.cache_dir, settings)?);
+        self.hash_map.insert(OVERWRITE_MOD, key);
+        Ok(())
+    }
+
+    /// Record the manifest of the files the working directory had before any mod was deployed, on the
+    /// first run. Vanilla files that are replaced by deployed mods are recorded from their backups, in case
+    /// the state predates the manifest.
+    fn record_baseline(&mut self) -> Result<(), ModError> {
+        info!("Recording the vanilla files of {}", self.working_dir.display());
+        let mut files = Vec::new();
+        let mut dirs = Vec::new();
+        walk(&self.working_dir, "", &mut files, &mut dirs)?;
+        // dirs mods are already deployed into may have been created for them
+        let tree = &self.current_active_tree;
+        self.baseline.dirs = dirs.into_iter().filter(|path| tree.node_at(path).is_none()).collect();
+        for path in files {
+            if self.current_active_tree.file_source(&path).is_none() {
+                let file = VanillaFile::of(&self.working_dir.join(&path[1..]))?;
+                self.baseline.files.insert(path, file);
+            }
+        }
+        let mut backups = Vec::new();
+        walk_files(&self.bak_dir, "", &mut backups)?;
+        for path in backups {
+            let file = VanillaFile::of(&self.bak_dir.join(&path[1..]))?;
+            self.baseline.files.insert(path, file);
+        }
+        self.baseline.save(&self.baseline_path)
+    }
+
+    /// What was done at startup about an interrupted deploy, if there was one.
+    pub fn recovery(&self) -> Option<&Recovery> {
+        self.recovery.as_ref()
+    }
+
+    fn recover(&mut self) -> Result<Option<Recovery>, ModError> {
+        let Some((journal, entries, mut journal_file)) = JournalFile::open(&self.journal_path)? else {
+            return Ok(None);
+        };
+        warn!("Found journal of an interrupted deploy: {}", self.journal_path.display());
+        let mut applied = 0;
+        let mut rolled_back_from = None;
+        for entry in entries {
+            match entry {
+                Entry::Applied(i) => applied = i as usize + 1,
+                Entry::RolledBack(i) => rolled_back_from = Some(i as usize),
+            }
+        }
+        if let Some(rolled_back_from) = rolled_back_from {
+            warn!("Resuming rollback of the interrupted deploy");
+            let ops = &journal.ops[..rolled_back_from];
+            return match self.roll_back(ops, &mut journal_file, ModError::DeployInterrupted) {
+                ModError::DeployRolledBack { rolled_back, .. } => {
+                    self.stamp_files(ops);
+                    self.save_state()?;
+                    journal_file.remove()?;
+                    Ok(Some(Recovery::RolledBack(rolled_back)))
+                }
+                e => Err(e),
+            };
+        }
+        warn!("Finishing the interrupted deploy ({} of {} operations were applied)", applied, journal.ops.len());
+        self.set_generated_files(journal.new_tree.node_of(GENERATED_MOD));
+        let remaining = journal.ops[applied..].to_vec();
+        match self.run_deploy(journal, journal_file, applied, true) {
+            Ok(()) => Ok(Some(Recovery::Finished(remaining))),
+            Err(ModError::DeployRolledBack { source, rolled_back }) => {
+                error!("Couldn't finish the interrupted deploy, rolled it back: {}", source);
+                Ok(Some(Recovery::RolledBack(rolled_back)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn restore_state(&mut self, state: State) {
+        info!("Restoring state from {}", self.state_path.display());
+        for (stored, active) in state
+            .active_mods
+            .into_iter()
+            .map(|stored| (stored, true))
+            .chain(state.inactive_mods.into_iter().map(|stored| (stored, false)))
+        {
+            // a mod that doesn't load is kept, so it is saved back as it was and can load again once its
+            // directory is fixed
+            let r#mod = match Mod::new(stored.dir.clone(), &self.cache_dir, stored.settings.clone()) {
+                Ok(r#mod) => r#mod,
+                Err(e) => {
+                    warn!("Couldn't reload mod {} from {}: {}", stored.uuid, stored.dir.display(), e);
+                    Mod::unloadable(stored.uuid, stored.dir.clone(), stored.settings, &e)
+                }
+            };
+            if r#mod.metadata.uuid != stored.uuid {
+                warn!("Mod at {} changed uuid: {} -> {}", stored.dir.display(), stored.uuid, r#mod.metadata.uuid);
+            }
+            let uuid = r#mod.metadata.uuid;
+            if let Some(strategy) = stored.strategy {
+                self.mod_strategies.insert(uuid, strategy);
+            }
+            let key = self.slotmap.insert(r#mod);
+            self.hash_map.insert(uuid, key);
+            if active {
+                self.active_mods.push(key);
+            } else {
+                self.inactive_mods.push(key);
+            }
+        }
+        if let Some(strategy) = state.overwrite_mod.strategy {
+            self.mod_strategies.insert(OVERWRITE_MOD, strategy);
+        }
+        self.current_active_tree = state.deployed_tree;
+        self.load_rules = state.load_rules;
+        self.deploy_strategy = state.deploy_strategy;
+        self.case_insensitive = state.case_insensitive;
+        self.deployed_files = state.deployed_files;
+    }
+
+    fn stored_mods(&self, keys: &[ModKey]) -> Vec<StoredMod> {
+        keys.iter()
+            .map(|&key| StoredMod {
+                uuid: self.slotmap[key].metadata.uuid,
+                dir: self.slotmap[key].dir.clone(),
+                strategy: self.mod_strategies.get(&self.slotmap[key].metadata.uuid).copied(),
+                settings: self.slotmap[key].settings.clone(),
+            })
+            .collect()
+    }
+
+    /// Save the registered mods, their order and the deployed tree next to the backup dir.
+    ///
+    /// This is done automatically by every method that changes the manager, so there is normally no
+    /// need to call it directly.
+    pub fn save_state(&self) -> Result<(), ModError> {
+        trace!("Saving state to {}", self.state_path.display());
+        State {
+            active_mods: self.stored_mods(&self.active_mods),
+            inactive_mods: self.stored_mods(&self.inactive_mods),
+            overwrite_mod: self.stored_mods(&[self.hash_map[&OVERWRITE_MOD]]).remove(0),
+            deployed_tree: self.current_active_tree.clone(),
+            load_rules: self.load_rules.clone(),
+            deploy_strategy: self.deploy_strategy,
+            case_insensitive: self.case_insensitive,
+            deployed_files: self.deployed_files.clone(),
+        }
+        .save(&self.state_path)
     }
 
     /// Get a list of active mods.
     ///
     /// # Examples
-    /// ```
+    /// ```no_run
     /// use modulate_lib::ModManager;
-    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap());
-    /// manager.add_mod("./mod1".into()).unwrap();
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let r#mod = manager.add_mod("./mod1".into()).unwrap();
     /// manager.add_mod("./mod2".into()).unwrap();
-    /// manager.activate_mod(0).unwrap();
+    /// manager.activate_mod(r#mod).unwrap();
     /// println!("{:#?}", manager.active_mods());
     /// ```
     pub fn active_mods(&self) -> Vec<&ModMetadata> {
@@ -96,9 +510,9 @@ impl ModManager {
     /// Get a list of inactive mods.
     ///
     /// # Examples
-    /// ```
+    /// ```no_run
     /// use modulate_lib::ModManager;
-    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap());
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
     /// manager.add_mod("./mod1".into()).unwrap();
     /// manager.add_mod("./mod2".into()).unwrap();
     /// println!("{:#?}", manager.inactive_mods());
@@ -113,36 +527,106 @@ impl ModManager {
     /// Add a mod to the manager. The mod will be inactive by default. Returns the uuid of the mod.
     ///
     /// # Examples
-    /// ```
+    /// ```no_run
     /// use modulate_lib::ModManager;
-    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap());
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
     /// manager.add_mod("./mod1".into()).unwrap();
     /// ```
     pub fn add_mod(&mut self, dir: PathBuf) -> Result<Uuid, ModError> {
-        let key = self.slotmap.insert(Mod::new(dir)?);
+        let r#mod = Mod::new(dir, &self.cache_dir, ModSettings::default())?;
+        if self.hash_map.contains_key(&r#mod.metadata.uuid) {
+            return Err(ModError::ModAlreadyAdded(r#mod.metadata.uuid));
+        }
+        let key = self.slotmap.insert(r#mod);
         self.inactive_mods.push(key);
         self.hash_map.insert(self.slotmap[key].metadata.uuid, key);
         info!("Added mod: {:#?}", self.slotmap[key].metadata.name);
+        self.save_state()?;
         Ok(self.slotmap[key].metadata.uuid)
     }
 
-    /// Remove a mod by uuid. The mod must be inactive.
+    /// Install a mod from a `.zip`, `.tar`, `.tar.gz` or `.tar.zst` archive. The archive is unpacked into
+    /// the mod library next to the backup dir and the mod is added like with [`ModManager::add_mod`].
+    ///
+    /// `mod.toml` may be at the top of the archive or inside a single top-level folder.
     ///
     /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let r#mod = manager.install_archive("./mod1.zip".into()).unwrap();
+    /// manager.activate_mod(r#mod).unwrap();
     /// ```
+    pub fn install_archive(&mut self, archive: PathBuf) -> Result<Uuid, ModError> {
+        fs::create_dir_all(&self.library_dir).at(&self.library_dir)?;
+        let unpack_dir = self.library_dir.join(format!(".unpacking-{}", Uuid::new_v4()));
+        let result = self.install_unpacked(&archive, &unpack_dir);
+        if unpack_dir.exists() {
+            if let Err(e) = fs::remove_dir_all(&unpack_dir) {
+                warn!("Couldn't clean up {}: {}", unpack_dir.display(), e);
+            }
+        }
+        result
+    }
+
+    fn install_unpacked(&mut self, archive: &Path, unpack_dir: &Path) -> Result<Uuid, ModError> {
+        info!("Unpacking {} into {}", archive.display(), unpack_dir.display());
+        archive::unpack(archive, unpack_dir)?;
+        let root = archive::find_mod_root(unpack_dir)?;
+        let uuid = ModMetadata::from_dir(&root)?.uuid;
+        if self.hash_map.contains_key(&uuid) {
+            return Err(ModError::ModAlreadyAdded(uuid));
+        }
+        let mod_dir = self.library_dir.join(uuid.to_string());
+        if mod_dir.exists() {
+            warn!("Replacing unregistered mod {} in the library", mod_dir.display());
+            fs::remove_dir_all(&mod_dir).at(&mod_dir)?;
+        }
+        fs::rename(&root, &mod_dir).at(&mod_dir)?;
+        self.add_mod(mod_dir)
+    }
+
+    /// The directory archives are installed into.
+    pub fn library_dir(&self) -> &Path {
+        &self.library_dir
+    }
+
+    /// Remove a mod by uuid. The mod must be inactive. A mod installed from an archive is deleted from the
+    /// mod library as well, see [`ModManager::install_archive`].
+    ///
+    /// # Examples
+    /// ```no_run
     /// use modulate_lib::ModManager;
-    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap());
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
     /// let r#mod = manager.add_mod("./mod1".into()).unwrap();
     /// manager.remove_mod(r#mod).unwrap();
     /// ```
     pub fn remove_mod(&mut self, uuid: Uuid) -> Result<(), ModError> {
+        if uuid == OVERWRITE_MOD || uuid == GENERATED_MOD {
+            return Err(ModError::BuiltInMod(uuid));
+        }
         if let Some(key) = self.hash_map.get(&uuid) {
             if self.active_mods.contains(key) {
                 return Err(ModError::InvalidModUuid(uuid));
             }
-            self.slotmap.remove(*key);
+            let key = *key;
+            let r#mod = self.slotmap.remove(key).unwrap();
+            self.inactive_mods.retain(|&k| k != key);
             self.hash_map.remove(&uuid);
+            self.mod_strategies.remove(&uuid);
+            let cache_path = Mod::cache_path(&self.cache_dir, uuid, &r#mod.dir);
+            if let Err(e) = fs::remove_file(&cache_path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Couldn't remove mod cache {}: {}", cache_path.display(), e);
+                }
+            }
             info!("Removed mod: {:#?}", uuid);
+            self.save_state()?;
+            // the library copy belongs to the manager, nothing else knows about it
+            if r#mod.dir.starts_with(&self.library_dir) && r#mod.dir.exists() {
+                info!("Deleting {} from the mod library", r#mod.dir.display());
+                fs::remove_dir_all(&r#mod.dir).at(&r#mod.dir)?;
+            }
             Ok(())
         } else {
             Err(ModError::InvalidModUuid(uuid))
@@ -151,22 +635,41 @@ impl ModManager {
 
     /// Activate a mod by uuid. The mod must be inactive.
     ///
+    /// Fails with [`ModError::IncompatibleMods`] if the mod and an active mod are declared incompatible.
+    /// Requirements that aren't active yet are only warned about, since they may be activated next;
+    /// [`ModManager::deploy_mods`] refuses to deploy until they are.
+    ///
     /// # Examples
-    /// ```
+    /// ```no_run
     /// use modulate_lib::ModManager;
-    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap());
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
     /// let r#mod = manager.add_mod("./mod1".into()).unwrap();
     /// manager.activate_mod(r#mod).unwrap();
     /// ```
     pub fn activate_mod(&mut self, uuid: Uuid) -> Result<(), ModError> {
+        if uuid == OVERWRITE_MOD || uuid == GENERATED_MOD {
+            return Err(ModError::BuiltInMod(uuid));
+        }
         if let Some(key) = self.hash_map.get(&uuid) {
             if self.active_mods.contains(key) {
                 return Err(ModError::InvalidModUuid(uuid));
             }
-            self.inactive_mods.retain(|&k| k != *key);
-            self.active_mods.push(*key);
-            info!("Activated mod: {:#?}", self.slotmap[*key].metadata.name);
-            Ok(())
+            let key = *key;
+            self.active_mods.push(key);
+            let problems = self.dependency_problems();
+            let incompatible = problems.iter().position(|problem| {
+                matches!(problem, ModError::IncompatibleMods { uuid: a, other: b, .. } if *a == uuid || *b == uuid)
+            });
+            if let Some(i) = incompatible {
+                self.active_mods.pop();
+                return Err(problems.into_iter().nth(i).unwrap());
+            }
+            for problem in problems {
+                warn!("{}", problem);
+            }
+            self.inactive_mods.retain(|&k| k != key);
+            info!("Activated mod: {:#?}", self.slotmap[key].metadata.name);
+            self.save_state()
         } else {
             Err(ModError::InvalidModUuid(uuid))
         }
@@ -175,14 +678,17 @@ impl ModManager {
     /// Deactivate a mod by uuid. The mod must be active.
     ///
     /// # Examples
-    /// ```
+    /// ```no_run
     /// use modulate_lib::ModManager;
-    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap());
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
     /// let r#mod = manager.add_mod("./mod1".into()).unwrap();
     /// manager.activate_mod(r#mod).unwrap();
     /// manager.deactivate_mod(r#mod).unwrap();
     /// ```
     pub fn deactivate_mod(&mut self, uuid: Uuid) -> Result<(), ModError> {
+        if uuid == OVERWRITE_MOD || uuid == GENERATED_MOD {
+            return Err(ModError::BuiltInMod(uuid));
+        }
         if let Some(key) = self.hash_map.get(&uuid) {
             if self.inactive_mods.contains(key) {
                 return Err(ModError::InvalidModUuid(uuid));
@@ -190,19 +696,18 @@ impl ModManager {
             self.active_mods.retain(|&k| k != *key);
             self.inactive_mods.push(*key);
             info!("Deactivated mod: {:#?}", self.slotmap[*key].metadata.name);
-            Ok(())
+            self.save_state()
         } else {
             Err(ModError::InvalidModUuid(uuid))
         }
     }
 
-    // TODO reorder by uuid instead of index
     /// Reorder the active mods by index. The order must contain all active mods.
     ///
     /// # Examples
-    /// ```
+    /// ```no_run
     /// use modulate_lib::ModManager;
-    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap());
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
     /// let mod1 = manager.add_mod("./mod1".into()).unwrap();
     /// let mod2 = manager.add_mod("./mod2".into()).unwrap();
     /// manager.activate_mod(mod1).unwrap();
@@ -219,106 +724,2190 @@ impl ModManager {
             new_active_mods.push(self.active_mods[*i]);
         }
         self.active_mods = new_active_mods;
-        Ok(())
+        self.save_state()
     }
 
-    /// Deploy the mods to the working directory.
+    /// Move an active mod to `position` in the active mods. Positions past the end move it to the end.
     ///
-    /// Changes made by adding, removing, or reordering mods will not be applied until this method is called.
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let mod1 = manager.add_mod("./mod1".into()).unwrap();
+    /// let mod2 = manager.add_mod("./mod2".into()).unwrap();
+    /// manager.activate_mod(mod1).unwrap();
+    /// manager.activate_mod(mod2).unwrap();
+    /// manager.move_mod(mod2, 0).unwrap();
+    /// ```
+    pub fn move_mod(&mut self, uuid: Uuid, position: usize) -> Result<(), ModError> {
+        let from = self.active_position(uuid)?;
+        let key = self.active_mods.remove(from);
+        let position = position.min(self.active_mods.len());
+        self.active_mods.insert(position, key);
+        info!("Moved mod {} to position {}", self.slotmap[key].metadata.name, position);
+        self.save_state()
+    }
+
+    /// Move an active mod so that it comes right before `other` in the active mods.
     ///
     /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let mod1 = manager.add_mod("./mod1".into()).unwrap();
+    /// let mod2 = manager.add_mod("./mod2".into()).unwrap();
+    /// manager.activate_mod(mod1).unwrap();
+    /// manager.activate_mod(mod2).unwrap();
+    /// manager.move_before(mod2, mod1).unwrap();
     /// ```
+    pub fn move_before(&mut self, uuid: Uuid, other: Uuid) -> Result<(), ModError> {
+        self.move_next_to(uuid, other, 0)
+    }
+
+    /// Move an active mod so that it comes right after `other` in the active mods.
+    ///
+    /// # Examples
+    /// ```no_run
     /// use modulate_lib::ModManager;
-    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap());
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
     /// let mod1 = manager.add_mod("./mod1".into()).unwrap();
     /// let mod2 = manager.add_mod("./mod2".into()).unwrap();
     /// manager.activate_mod(mod1).unwrap();
     /// manager.activate_mod(mod2).unwrap();
-    /// manager.reorder_mods(&[1, 0]).unwrap();
-    /// manager.apply_mods();
+    /// manager.move_after(mod1, mod2).unwrap();
     /// ```
-    pub fn deploy_mods(&mut self) {
-        let new_tree = self.make_tree();
-        let mut ops = Vec::new();
-        self.current_active_tree.tree_edit_distance(&new_tree, &mut ops, "");
-        self.apply_operations(ops);
-        self.current_active_tree = new_tree;
+    pub fn move_after(&mut self, uuid: Uuid, other: Uuid) -> Result<(), ModError> {
+        self.move_next_to(uuid, other, 1)
     }
 
-    fn make_tree(&self) -> SourcedNode {
-        let mut tree = SourcedNode::Dir {
-            name: "root".to_string(),
-            children: HashMap::new(),
-        };
-        info!("Calculating virtual tree");
-        for key in self.active_mods.iter().rev() {
-            trace!(" - Adding mod: {}", self.slotmap[*key].metadata.name);
-            let mod_node = &self.slotmap[*key].node;
-            tree.overwrite_with(mod_node, *key);
+    fn move_next_to(&mut self, uuid: Uuid, other: Uuid, offset: usize) -> Result<(), ModError> {
+        let from = self.active_position(uuid)?;
+        self.active_position(other)?;
+        if uuid == other {
+            return Ok(());
         }
-        tree
+        let key = self.active_mods.remove(from);
+        let to = self.active_position(other)? + offset;
+        self.active_mods.insert(to, key);
+        info!("Moved mod {} to position {}", self.slotmap[key].metadata.name, to);
+        self.save_state()
     }
 
-    // TODO remove unwraps and handle/return errors
-    fn apply_operations(&mut self, ops: Vec<Operation>) {
-        for op in ops {
-            let path = &op.path[1..];
-            let working_file = self.working_dir.join(path);
-            let back_file = self.bak_dir.join(path);
-
-            match op.kind {
-                OperationKind::CreateDir => {
-                    info!("Creating dir: {}", working_file.display());
-                    fs::create_dir_all(working_file).unwrap();
-                }
-                OperationKind::RemoveDir => {
-                    if working_file.read_dir().unwrap().next().is_none() {
-                        info!("Removing dir: {}", working_file.display());
-                        fs::remove_dir(working_file).unwrap();
-                    }
-                }
-                OperationKind::CreateFile(source) => {
-                    let mod_file = self.slotmap[source].dir.join(path);
-                    info!("Creating file with hard link: {} -> {} ({})", mod_file.display(), working_file.display(), self.slotmap[source].metadata.name);
-                    // check if file exists
-                    if working_file.exists() {
-                        if !back_file.exists() {
-                            trace!(" - Creating backup: {}", back_file.display());
-                            fs::create_dir_all(back_file.parent().unwrap()).unwrap();
-                            fs::hard_link(&working_file, back_file).unwrap();
-                        }
-                        trace!(" - Removing file: {}", working_file.display());
-                        fs::remove_file(&working_file).unwrap();
-                    }
-                    fs::create_dir_all(working_file.parent().unwrap()).unwrap();
-                    trace!(" - Creating hard link");
-                    fs::hard_link(mod_file, working_file).unwrap();
-                }
-                OperationKind::RemoveFile => {
-                    info!("Removing file: {}", working_file.display());
-                    fs::remove_file(&working_file).unwrap();
-                    if back_file.exists() {
-                        trace!(" - Restoring backup with hard link: {} -> {}", back_file.display(), working_file.display());
-                        fs::hard_link(&back_file, &working_file).unwrap();
-                        fs::remove_file(back_file).unwrap();
-                    }
-                }
-                OperationKind::ChangeSource(new_source) => {
-                    info!("Changing source: {} ({})", working_file.display(), self.slotmap[new_source].metadata.name);
-                    let mod_file = self.slotmap[new_source].dir.join(path);
-                    if working_file.exists() {
-                        trace!(" - Removing file: {}", working_file.display());
-                        fs::remove_file(&working_file).unwrap();
+    /// Replace the order of the active mods. `order` must list every active mod exactly once; otherwise
+    /// [`ModError::InvalidModUuidOrder`] lists each uuid that is unknown, inactive, missing or duplicated.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let mod1 = manager.add_mod("./mod1".into()).unwrap();
+    /// let mod2 = manager.add_mod("./mod2".into()).unwrap();
+    /// manager.activate_mod(mod1).unwrap();
+    /// manager.activate_mod(mod2).unwrap();
+    /// manager.set_order(&[mod2, mod1]).unwrap();
+    /// ```
+    pub fn set_order(&mut self, order: &[Uuid]) -> Result<(), ModError> {
+        let mut unknown = Vec::new();
+        let mut inactive = Vec::new();
+        let mut duplicated = Vec::new();
+        let mut new_active_mods = Vec::new();
+        for uuid in order {
+            match self.hash_map.get(uuid) {
+                None => unknown.push(*uuid),
+                Some(key) if !self.active_mods.contains(key) => inactive.push(*uuid),
+                Some(key) if new_active_mods.contains(key) => {
+                    if !duplicated.contains(uuid) {
+                        duplicated.push(*uuid);
                     }
-                    fs::create_dir_all(working_file.parent().unwrap()).unwrap();
-                    trace!(" - Creating hard link: {} -> {}", working_file.display(), mod_file.display());
-                    fs::hard_link(mod_file, working_file).unwrap();
                 }
+                Some(key) => new_active_mods.push(*key),
             }
         }
+        let missing: Vec<Uuid> = self
+            .active_mods
+            .iter()
+            .filter(|key| !new_active_mods.contains(key))
+            .map(|&key| self.slotmap[key].metadata.uuid)
+            .collect();
+        if !unknown.is_empty() || !inactive.is_empty() || !missing.is_empty() || !duplicated.is_empty() {
+            return Err(ModError::InvalidModUuidOrder {
+                unknown,
+                inactive,
+                missing,
+                duplicated,
+            });
+        }
+        self.active_mods = new_active_mods;
+        self.save_state()
     }
 
-    pub fn print_tree(&self) {
-        self.current_active_tree.print(0);
+    fn active_position(&self, uuid: Uuid) -> Result<usize, ModError> {
+        let key = self.hash_map.get(&uuid).ok_or(ModError::InvalidModUuid(uuid))?;
+        self.active_mods
+            .iter()
+            .position(|k| k == key)
+            .ok_or(ModError::ModNotActive(uuid))
+    }
+
+    /// Add a load order rule of your own, on top of the `load_after`/`load_before` rules the mods declare.
+    /// Both mods must be registered. Rules are applied by [`ModManager::sort_mods`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::{LoadRule, ModManager};
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let mod1 = manager.add_mod("./mod1".into()).unwrap();
+    /// let mod2 = manager.add_mod("./mod2".into()).unwrap();
+    /// // mod2's files win over mod1's
+    /// manager.add_load_rule(LoadRule { before: mod1, after: mod2 }).unwrap();
+    /// ```
+    pub fn add_load_rule(&mut self, rule: LoadRule) -> Result<(), ModError> {
+        for uuid in [rule.before, rule.after] {
+            self.mod_by_uuid(uuid)?;
+        }
+        if !self.load_rules.contains(&rule) {
+            info!("Added load rule: {} before {}", rule.before, rule.after);
+            self.load_rules.push(rule);
+        }
+        self.save_state()
+    }
+
+    /// Remove a load order rule added with [`ModManager::add_load_rule`]. Returns whether it existed.
+    pub fn remove_load_rule(&mut self, rule: &LoadRule) -> Result<bool, ModError> {
+        let len = self.load_rules.len();
+        self.load_rules.retain(|r| r != rule);
+        self.save_state()?;
+        Ok(self.load_rules.len() != len)
+    }
+
+    /// The load order rules added with [`ModManager::add_load_rule`].
+    pub fn load_rules(&self) -> &[LoadRule] {
+        &self.load_rules
+    }
+
+    /// Sort the active mods so that every load order rule, declared by the mods or added by the user, is
+    /// satisfied. Mods that no rule constrains keep their current relative order.
+    ///
+    /// Fails with [`ModError::LoadOrderCycle`] if the rules contradict each other.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let mod1 = manager.add_mod("./mod1".into()).unwrap();
+    /// let mod2 = manager.add_mod("./mod2".into()).unwrap();
+    /// manager.activate_mod(mod1).unwrap();
+    /// manager.activate_mod(mod2).unwrap();
+    /// manager.sort_mods().unwrap();
+    /// ```
+    pub fn sort_mods(&mut self) -> Result<(), ModError> {
+        let uuids: Vec<Uuid> = self.active_mods.iter().map(|&key| self.slotmap[key].metadata.uuid).collect();
+        let mut rules = self.load_rules.clone();
+        for &key in &self.active_mods {
+            let metadata = &self.slotmap[key].metadata;
+            rules.extend(metadata.load_after.iter().map(|&other| LoadRule {
+                before: other,
+                after: metadata.uuid,
+            }));
+            rules.extend(metadata.load_before.iter().map(|&other| LoadRule {
+                before: metadata.uuid,
+                after: other,
+            }));
+        }
+        // whichever loads after goes first in the active mods
+        let edges: Vec<(Uuid, Uuid)> = rules.iter().map(|rule| (rule.after, rule.before)).collect();
+        match stable_topological_sort(&uuids, &edges) {
+            Ok(sorted) => {
+                self.active_mods = sorted.iter().map(|uuid| self.hash_map[uuid]).collect();
+                info!("Sorted active mods");
+                self.save_state()
+            }
+            Err(chain) => Err(ModError::LoadOrderCycle {
+                names: chain.iter().map(|&uuid| self.slotmap[self.hash_map[&uuid]].metadata.name.clone()).collect(),
+                chain,
+            }),
+        }
+    }
+
+    /// How mod files are deployed, unless a mod has its own strategy.
+    pub fn deploy_strategy(&self) -> DeployStrategy {
+        self.deploy_strategy
+    }
+
+    /// Change how mod files are deployed. Only files deployed from now on are affected; files that are
+    /// already deployed stay as they are until they are removed or replaced.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::{DeployStrategy, ModManager};
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// manager.set_deploy_strategy(DeployStrategy::RelativeSymlink).unwrap();
+    /// manager.deploy_mods().unwrap();
+    /// ```
+    pub fn set_deploy_strategy(&mut self, strategy: DeployStrategy) -> Result<(), ModError> {
+        self.deploy_strategy = strategy;
+        info!("Deploy strategy set to {}", strategy);
+        self.save_state()
+    }
+
+    /// The strategy set for a mod with [`ModManager::set_mod_deploy_strategy`], if any.
+    pub fn mod_deploy_strategy(&self, uuid: Uuid) -> Option<DeployStrategy> {
+        self.mod_strategies.get(&uuid).copied()
+    }
+
+    /// Deploy the files of one mod with `strategy` instead of the manager's, or go back to the manager's
+    /// with `None`. Like [`ModManager::set_deploy_strategy`], this only affects files deployed from now on.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::{DeployStrategy, ModManager};
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let r#mod = manager.add_mod("./mod1".into()).unwrap();
+    /// manager.set_mod_deploy_strategy(r#mod, Some(DeployStrategy::Copy)).unwrap();
+    /// ```
+    pub fn set_mod_deploy_strategy(&mut self, uuid: Uuid, strategy: Option<DeployStrategy>) -> Result<(), ModError> {
+        self.mod_by_uuid(uuid)?;
+        match strategy {
+            Some(strategy) => self.mod_strategies.insert(uuid, strategy),
+            None => self.mod_strategies.remove(&uuid),
+        };
+        self.save_state()
+    }
+
+    /// The ignore rules set for a mod with [`ModManager::set_mod_ignore_rules`].
+    pub fn mod_ignore_rules(&self, uuid: Uuid) -> Result<&[String], ModError> {
+        Ok(&self.mod_by_uuid(uuid)?.settings.ignore)
+    }
+
+    /// Leave the files of a mod that match any of the globs in `rules` out of the deploy, on top of those
+    /// its `mod.toml` excludes. Globs match paths relative to the mod directory, and one without a `/`
+    /// matches names anywhere in the mod. Replaces the rules set before, an empty list removes them.
+    ///
+    /// Like every change to the mods, this is applied by the next deploy.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let r#mod = manager.add_mod("./mod1".into()).unwrap();
+    /// manager.set_mod_ignore_rules(r#mod, vec!["*.txt".to_string(), "docs/".to_string()]).unwrap();
+    /// manager.deploy_mods().unwrap();
+    /// ```
+    pub fn set_mod_ignore_rules(&mut self, uuid: Uuid, rules: Vec<String>) -> Result<(), ModError> {
+        if uuid == GENERATED_MOD {
+            return Err(ModError::BuiltInMod(uuid));
+        }
+        self.mod_by_uuid(uuid)?;
+        filter::validate(&rules)?;
+        let key = self.hash_map[&uuid];
+        let settings = ModSettings {
+            ignore: rules,
+            ..self.slotmap[key].settings.clone()
+        };
+        self.reload_mod(key, settings)?;
+        self.save_state()
+    }
+
+    /// The choices made for the option groups of a mod, by group name. Groups the user didn't choose for
+    /// have their defaults.
+    pub fn mod_choices(&self, uuid: Uuid) -> Result<BTreeMap<String, Vec<String>>, ModError> {
+        let r#mod = self.mod_by_uuid(uuid)?;
+        Ok(r#mod
+            .metadata
+            .options
+            .iter()
+            .map(|group| {
+                let chosen = group.chosen(r#mod.settings.choices.get(&group.name).map(Vec::as_slice));
+                (group.name.clone(), chosen.into_iter().map(|choice| choice.name.clone()).collect())
+            })
+            .collect())
+    }
+
+    /// Choose which options of the group `group` of a mod are deployed, or go back to the defaults of the
+    /// mod with `None`. A single-choice group takes exactly one choice, a multi-choice group any number.
+    ///
+    /// Like every change to the mods, this is applied by the next deploy.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let r#mod = manager.add_mod("./mod1".into()).unwrap();
+    /// manager.set_mod_choice(r#mod, "Textures", Some(vec!["4K".to_string()])).unwrap();
+    /// manager.deploy_mods().unwrap();
+    /// ```
+    pub fn set_mod_choice(&mut self, uuid: Uuid, group: &str, choices: Option<Vec<String>>) -> Result<(), ModError> {
+        let r#mod = self.mod_by_uuid(uuid)?;
+        let option_group = r#mod
+            .metadata
+            .option_group(group)
+            .ok_or_else(|| ModError::InvalidChoice(format!("{} has no option group {:?}", r#mod.metadata.name, group)))?;
+        let mut settings = r#mod.settings.clone();
+        match choices {
+            Some(choices) => {
+                option_group.validate(&choices)?;
+                settings.choices.insert(group.to_string(), choices);
+            }
+            None => {
+                settings.choices.remove(group);
+            }
+        }
+        self.reload_mod(self.hash_map[&uuid], settings)?;
+        self.save_state()
+    }
+
+    /// Load a registered mod again, after its files or `settings` changed.
+    fn reload_mod(&mut self, key: ModKey, settings: ModSettings) -> Result<(), ModError> {
+        self.slotmap[key] = Mod::new(self.slotmap[key].dir.clone(), &self.cache_dir, settings)?;
+        Ok(())
+    }
+
+    /// Whether paths that only differ in case are merged, see [`ModManager::set_case_insensitive`].
+    pub fn case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+
+    /// Merge paths that only differ in case, like `Textures/a.dds` and `textures/A.dds`, as Windows does.
+    /// Games built for Windows and run on Linux, through Proton for example, expect this.
+    ///
+    /// The spelling the working directory already has wins, then that of the mod loaded first. Files keep
+    /// their spelling when a mod loaded later replaces them. Like every change to the mods, this is applied
+    /// by the next deploy.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// manager.set_case_insensitive(true).unwrap();
+    /// manager.deploy_mods().unwrap();
+    /// ```
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) -> Result<(), ModError> {
+        self.case_insensitive = case_insensitive;
+        info!("Case-insensitive merging {}", if case_insensitive { "enabled" } else { "disabled" });
+        self.save_state()
+    }
+
+    /// Deploy the mods to the working directory.
+    ///
+    /// Changes made by adding, removing, or reordering mods will not be applied until this method is called.
+    ///
+    /// The deploy is journaled next to the backup dir. If any operation fails, the ones already applied are
+    /// undone and [`ModError::DeployRolledBack`] says which, leaving the working directory as it was.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let mod1 = manager.add_mod("./mod1".into()).unwrap();
+    /// let mod2 = manager.add_mod("./mod2".into()).unwrap();
+    /// manager.activate_mod(mod1).unwrap();
+    /// manager.activate_mod(mod2).unwrap();
+    /// manager.reorder_mods(&[1, 0]).unwrap();
+    /// manager.deploy_mods().unwrap();
+    /// ```
+    pub fn deploy_mods(&mut self) -> Result<(), ModError> {
+        let plan = self.plan_deploy()?;
+        self.apply_plan(plan)
+    }
+
+    /// Work out what [`ModManager::deploy_mods`] would do, without changing anything on disk.
+    ///
+    /// Fails if any active mod has an unmet requirement or is incompatible with another active mod, see
+    /// [`ModManager::dependency_problems`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let mod1 = manager.add_mod("./mod1".into()).unwrap();
+    /// manager.activate_mod(mod1).unwrap();
+    /// for step in &manager.plan_deploy().unwrap().operations {
+    ///     println!("{}", step.operation);
+    /// }
+    /// ```
+    pub fn plan_deploy(&self) -> Result<DeployPlan, ModError> {
+        if let Some(problem) = self.dependency_problems().into_iter().next() {
+            return Err(problem);
+        }
+        let changes = self.verify()?;
+        if !changes.is_empty() {
+            return Err(ModError::DeployedFilesChanged(changes));
+        }
+        let mut new_tree = self.make_tree(&mut Vec::new());
+        let mut rejected_hunks = Vec::new();
+        let generated = self.generate(&mut new_tree, &mut rejected_hunks)?;
+        let mut ops = Vec::new();
+        self.current_active_tree.tree_edit_distance(&new_tree, &mut ops, "");
+        Ok(DeployPlan {
+            operations: ops.into_iter().map(|op| self.plan_operation(op)).collect(),
+            rejected_hunks,
+            base_tree: self.current_active_tree.clone(),
+            new_tree,
+            generated,
+        })
+    }
+
+    fn plan_operation(&self, operation: Operation) -> PlannedOperation {
+        let path = &operation.path[1..];
+        let planned_file = |uuid| {
+            self.mod_by_uuid(uuid).ok().map(|source| PlannedFile {
+                mod_uuid: uuid,
+                mod_name: source.metadata.name.clone(),
+                path: source.file_path(path),
+            })
+        };
+        let (source, previous) = match operation.kind {
+            OperationKind::CreateDir | OperationKind::RemoveDir => (None, None),
+            OperationKind::CreateFile(source) => (planned_file(source), None),
+            OperationKind::RemoveFile(previous) => (None, planned_file(previous)),
+            OperationKind::ChangeSource { from, to } => (planned_file(to), planned_file(from)),
+        };
+        PlannedOperation {
+            target: self.working_dir.join(path),
+            operation,
+            source,
+            previous,
+        }
+    }
+
+    /// Carry out a plan made by [`ModManager::plan_deploy`], exactly as it was made.
+    ///
+    /// Fails with [`ModError::StalePlan`] if something else was deployed in the meantime.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let mod1 = manager.add_mod("./mod1".into()).unwrap();
+    /// manager.activate_mod(mod1).unwrap();
+    /// let plan = manager.plan_deploy().unwrap();
+    /// manager.apply_plan(plan).unwrap();
+    /// ```
+    pub fn apply_plan(&mut self, plan: DeployPlan) -> Result<(), ModError> {
+        if plan.base_tree != self.current_active_tree {
+            return Err(ModError::StalePlan);
+        }
+        for step in &plan.operations {
+            if let Some(uuid) = step.operation.kind.source() {
+                self.mod_by_uuid(uuid)?;
+            }
+        }
+        let generated_dir = self.mod_by_uuid(GENERATED_MOD)?.dir.clone();
+        for file in &plan.generated {
+            file.write(&generated_dir)?;
+        }
+        let journal = Journal {
+            new_tree: plan.new_tree,
+            ops: plan.operations.into_iter().map(|step| step.operation).collect(),
+        };
+        self.record_vanilla_dirs(&journal.ops)?;
+        let journal_file = JournalFile::create(&self.journal_path, &journal)?;
+        self.set_generated_files(journal.new_tree.node_of(GENERATED_MOD));
+        let result = self.run_deploy(journal, journal_file, 0, false);
+        self.set_generated_files(self.current_active_tree.node_of(GENERATED_MOD));
+        result
+    }
+
+    /// Add the dirs `ops` create that the working directory already has to the vanilla dirs, so that
+    /// removing them later, or rolling back, leaves them there.
+    fn record_vanilla_dirs(&mut self, ops: &[Operation]) -> Result<(), ModError> {
+        let mut recorded = false;
+        for op in ops.iter().filter(|op| matches!(op.kind, OperationKind::CreateDir)) {
+            if self.working_dir.join(&op.path[1..]).is_dir() && !self.baseline.dirs.contains(&op.path) {
+                info!("Recording vanilla dir {}", op.path);
+                self.baseline.dirs.insert(op.path.clone());
+                recorded = true;
+            }
+        }
+        if recorded {
+            self.baseline.save(&self.baseline_path)?;
+        }
+        Ok(())
+    }
+
+    /// Merge the config fragments and apply the patches of the active mods to the files they change, and
+    /// lay the results over `tree` as files of the generated mod.
+    ///
+    /// The fragments and patches of a file are applied in load order, starting from the file `tree` deploys
+    /// at its path, or the vanilla file if no mod replaces it. Hunks that don't apply are left out, logged
+    /// and collected into `rejected`.
+    fn generate(&self, tree: &mut SourcedNode, rejected: &mut Vec<RejectedHunk>) -> Result<Vec<GeneratedFile>, ModError> {
+        let mut fragments: BTreeMap<&str, Vec<(&Mod, &Fragment)>> = BTreeMap::new();
+        let keys = self.active_mods.iter().rev().chain([&self.hash_map[&OVERWRITE_MOD]]);
+        for r#mod in keys.map(|&key| &self.slotmap[key]) {
+            for fragment in &r#mod.fragments {
+                fragments.entry(&fragment.path).or_default().push((r#mod, fragment));
+            }
+        }
+        let mut generated = Vec::new();
+        for (path, fragments) in fragments {
+            let base = match tree.node_at(path) {
+                Some(SourcedNode::File { source, .. }) => Some(self.mod_by_uuid(*source)?.file_path(path)),
+                Some(SourcedNode::Dir { .. }) => {
+                    warn!("Not changing {} with config fragments or patches, a mod has a dir there", path);
+                    continue;
+                }
+                None => self.vanilla_file(path),
+            };
+            // the file is written in the encoding of its base, or of its first fragment if it is new
+            let mut encoding = None;
+            let mut contents = match base {
+                Some(base) => {
+                    let (text, base_encoding) = TextEncoding::read(&base)?;
+                    encoding = Some(base_encoding);
+                    Some((text, base))
+                }
+                None => None,
+            };
+            for (r#mod, fragment) in fragments {
+                let file = r#mod.dir.join(&fragment.file);
+                let (text, fragment_encoding) = TextEncoding::read(&file)?;
+                encoding.get_or_insert(fragment_encoding);
+                let changed = match fragment.kind {
+                    FragmentKind::Merge => {
+                        let Some(format) = ConfigFormat::of(path) else {
+                            return Err(ModError::MergeFailed {
+                                path: file,
+                                reason: "only INI, JSON and TOML files can be merged".to_string(),
+                            });
+                        };
+                        let base = contents.as_ref().map(|(text, base)| (base.as_path(), text.as_str()));
+                        format.merge(base, (&file, &text))?
+                    }
+                    FragmentKind::Patch => {
+                        let patch = Patch::parse(&text).map_err(|reason| ModError::InvalidPatch {
+                            path: file.clone(),
+                            reason,
+                        })?;
+                        let (patched, hunks) = patch.apply(contents.as_ref().map_or("", |(text, _)| text));
+                        for hunk in hunks {
+                            info!("Hunk {} of {} doesn't apply to {}", hunk, file.display(), path);
+                            rejected.push(RejectedHunk {
+                                target: PathBuf::from(&path[1..]),
+                                mod_uuid: r#mod.metadata.uuid,
+                                mod_name: r#mod.metadata.name.clone(),
+                                patch: PathBuf::from(&fragment.file),
+                                hunk,
+                            });
+                        }
+                        patched
+                    }
+                };
+                contents = Some((changed, file));
+            }
+            // there is at least one fragment for every path
+            let (contents, _) = contents.unwrap();
+            let file = GeneratedFile::new(encoding.unwrap().encode(&contents));
+            tree.set_file_source(path, GENERATED_MOD, Some(file.name.clone()));
+            generated.push(file);
+        }
+        Ok(generated)
+    }
+
+    /// The vanilla file at `path`, if the game has one: in the backup dir if something is deployed over
+    /// it, in the working directory otherwise.
+    fn vanilla_file(&self, path: &str) -> Option<PathBuf> {
+        let file = match self.current_active_tree.node_at(path) {
+            Some(_) => self.bak_dir.join(&path[1..]),
+            None => self.working_dir.join(&path[1..]),
+        };
+        file.is_file().then_some(file)
+    }
+
+    /// Make `node` the tree of the generated mod, and delete the generated files nothing deploys anymore.
+    fn set_generated_files(&mut self, node: Node) {
+        let key = self.hash_map[&GENERATED_MOD];
+        self.slotmap[key].node = node;
+        let deployed_node = self.current_active_tree.node_of(GENERATED_MOD);
+        let mut used = Vec::new();
+        deployed_node.origins(&mut used);
+        self.slotmap[key].node.origins(&mut used);
+        let Ok(entries) = fs::read_dir(&self.slotmap[key].dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !used.contains(&name.as_str()) {
+                trace!("Deleting generated file {}", name);
+                if let Err(e) = fs::remove_file(entry.path()) {
+                    warn!("Couldn't delete generated file {}: {}", entry.path().display(), e);
+                }
+            }
+        }
+    }
+
+    /// Compare the working directory against the vanilla files recorded when the manager first saw it,
+    /// to notice changes that don't come from a deploy, such as a game update. Files that are neither
+    /// vanilla nor deployed are reported as added.
+    ///
+    /// An update that replaces a file a mod also replaces makes the backup of that file outdated; see
+    /// [`ModManager::refresh_vanilla`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// for change in manager.vanilla_changes().unwrap() {
+    ///     println!("{}: {:?}", change.path, change.kind);
+    /// }
+    /// ```
+    pub fn vanilla_changes(&self) -> Result<Vec<VanillaChange>, ModError> {
+        let mut changes = Vec::new();
+        for (path, file) in &self.baseline.files {
+            let kind = match self.current_active_tree.node_at(path) {
+                Some(SourcedNode::File { source, .. }) => match self.check_deployed_file(path, *source)? {
+                    // a file edited in place is still the deployed one, so it is a change for `verify`; only
+                    // a new file at the path can be an update of the vanilla one
+                    Some(ChangeKind::Replaced) => Some(VanillaChangeKind::BackupStale),
+                    _ => None,
+                },
+                // a mod has a dir where the game has this file, it is backed up
+                Some(SourcedNode::Dir { .. }) => None,
+                None => match file.matches(&self.working_dir.join(&path[1..]))? {
+                    None => Some(VanillaChangeKind::Removed),
+                    Some(false) => Some(VanillaChangeKind::Modified),
+                    Some(true) => None,
+                },
+            };
+            if let Some(kind) = kind {
+                changes.push(VanillaChange { path: path.clone(), kind });
+            }
+        }
+        let mut files = Vec::new();
+        walk_files(&self.working_dir, "", &mut files)?;
+        for path in files {
+            if !self.baseline.files.contains_key(&path) && self.current_active_tree.node_at(&path).is_none() {
+                changes.push(VanillaChange {
+                    path,
+                    kind: VanillaChangeKind::Added,
+                });
+            }
+        }
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(changes)
+    }
+
+    /// Accept the changes found by [`ModManager::vanilla_changes`] as the new vanilla files, after a game
+    /// update. Outdated backups are replaced by the files the update put in place of deployed ones, and the
+    /// mod files are deployed again over them. Added files become vanilla files, so files the game wrote
+    /// that should go into the overwrite mod are best captured first, see
+    /// [`ModManager::capture_new_files`]. Returns the changes that were accepted.
+    ///
+    /// Deployed files that were edited in place are never taken as vanilla files, since that is what a game
+    /// writing its own config looks like; they are left to [`ModManager::verify`] and
+    /// [`ModManager::resolve_change`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// manager.refresh_vanilla().unwrap();
+    /// manager.purge().unwrap();
+    /// ```
+    pub fn refresh_vanilla(&mut self) -> Result<Vec<VanillaChange>, ModError> {
+        let changes = self.vanilla_changes()?;
+        let mut redeployed = Vec::new();
+        let result = self.refresh_vanilla_files(&changes, &mut redeployed);
+        self.stamp_files(&redeployed);
+        self.save_state()?;
+        self.baseline.save(&self.baseline_path)?;
+        result.map(|()| changes)
+    }
+
+    fn refresh_vanilla_files(&mut self, changes: &[VanillaChange], redeployed: &mut Vec<Operation>) -> Result<(), ModError> {
+        for change in changes {
+            let path = &change.path;
+            let working_file = self.working_dir.join(&path[1..]);
+            match change.kind {
+                VanillaChangeKind::Removed => {
+                    info!("Forgetting removed vanilla file {}", path);
+                    self.baseline.files.remove(path);
+                }
+                VanillaChangeKind::Modified | VanillaChangeKind::Added => {
+                    info!("Recording updated vanilla file {}", path);
+                    self.baseline.files.insert(path.clone(), VanillaFile::of(&working_file)?);
+                }
+                VanillaChangeKind::BackupStale => {
+                    let source = self.current_active_tree.file_source(path).unwrap();
+                    let back_file = self.bak_dir.join(&path[1..]);
+                    info!("Refreshing backup {}", back_file.display());
+                    copy_replacing(&working_file, &back_file)?;
+                    fs::remove_file(&working_file).at(&working_file)?;
+                    self.baseline.files.insert(path.clone(), VanillaFile::of(&back_file)?);
+                    let op = Operation {
+                        kind: OperationKind::ChangeSource { from: source, to: source },
+                        path: path.clone(),
+                    };
+                    self.apply_operation(&op, false)?;
+                    redeployed.push(op);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo every deploy: remove every deployed file, restore every backed up game file and remove the
+    /// directories that were created for mods, leaving the working directory as it was before any mod was
+    /// deployed. The active mods and their order are kept, so the next deploy puts everything back.
+    ///
+    /// Like a deploy, this is journaled and rolled back if it fails, and refuses to run while deployed
+    /// files were changed, see [`ModManager::verify`]. If the changes look like a game update made the
+    /// backups outdated, it fails with [`ModError::StaleBackups`] instead, see
+    /// [`ModManager::refresh_vanilla`]. Afterwards, any backup still in the backup dir is
+    /// restored as well, along with removing the deployed file in its way, if the deployed tree somehow
+    /// lost track of it. Fails with [`ModError::BackupsLeftBehind`] if some can't be.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// manager.purge().unwrap();
+    /// ```
+    pub fn purge(&mut self) -> Result<(), ModError> {
+        let stale: Vec<String> = self
+            .vanilla_changes()?
+            .into_iter()
+            .filter(|change| change.kind == VanillaChangeKind::BackupStale)
+            .map(|change| change.path)
+            .collect();
+        if !stale.is_empty() {
+            return Err(ModError::StaleBackups(stale));
+        }
+        let changes: Vec<ChangedFile> = self
+            .verify()?
+            .into_iter()
+            .filter(|change| change.kind != ChangeKind::Removed)
+            .collect();
+        if !changes.is_empty() {
+            return Err(ModError::DeployedFilesChanged(changes));
+        }
+        let new_tree = SourcedNode::Dir {
+            name: "root".to_string(),
+            children: HashMap::new(),
+        };
+        let mut ops = Vec::new();
+        self.current_active_tree.tree_edit_distance(&new_tree, &mut ops, "");
+        info!("Purging {} operations", ops.len());
+        let journal = Journal { new_tree, ops };
+        let journal_file = JournalFile::create(&self.journal_path, &journal)?;
+        self.run_deploy(journal, journal_file, 0, false)?;
+        let result = self.restore_leftover_backups();
+        self.set_generated_files(self.current_active_tree.node_of(GENERATED_MOD));
+        result
+    }
+
+    /// Restore whatever is still in the backup dir once nothing is deployed.
+    fn restore_leftover_backups(&self) -> Result<(), ModError> {
+        let mut backups = Vec::new();
+        walk_files(&self.bak_dir, "", &mut backups)?;
+        let mut left_behind = Vec::new();
+        for path in backups {
+            let back_file = self.bak_dir.join(&path[1..]);
+            let working_file = self.working_dir.join(&path[1..]);
+            if exists(&working_file) {
+                let deployed = self
+                    .slotmap
+                    .values()
+                    .any(|r#mod| deploy::is_deployed(&working_file, &r#mod.file_path(&path[1..]), false));
+                if !deployed {
+                    warn!("Can't restore backup {}, {} is in the way", back_file.display(), working_file.display());
+                    left_behind.push(back_file);
+                    continue;
+                }
+                fs::remove_file(&working_file).at(&working_file)?;
+            }
+            info!("Restoring leftover backup {}", back_file.display());
+            create_parent_dir(&working_file).at(&working_file)?;
+            fs::rename(&back_file, &working_file).at(&back_file)?;
+        }
+        remove_empty_dirs(&self.bak_dir)?;
+        if !left_behind.is_empty() {
+            return Err(ModError::BackupsLeftBehind(left_behind));
+        }
+        Ok(())
+    }
+
+    /// Apply the journaled operations from `start` on, then make the new tree current. With `resuming`
+    /// set, the operation at `start` may have been interrupted halfway.
+    fn run_deploy(
+        &mut self,
+        journal: Journal,
+        mut journal_file: JournalFile,
+        start: usize,
+        resuming: bool,
+    ) -> Result<(), ModError> {
+        if let Err(e) = self.apply_operations(&journal.ops, start, resuming, &mut journal_file) {
+            self.stamp_files(&journal.ops[start..]);
+            self.save_state()?;
+            if matches!(e, ModError::DeployRolledBack { .. }) {
+                journal_file.remove()?;
+            }
+            return Err(e);
+        }
+        self.current_active_tree = journal.new_tree;
+        self.stamp_files(&journal.ops[start..]);
+        self.save_state()?;
+        journal_file.remove()
+    }
+
+    /// Record what the files touched by `ops` look like now, so [`ModManager::verify`] can tell if they are
+    /// changed later.
+    fn stamp_files(&mut self, ops: &[Operation]) {
+        for op in ops {
+            if matches!(op.kind, OperationKind::CreateDir | OperationKind::RemoveDir) {
+                continue;
+            }
+            if self.current_active_tree.file_source(&op.path).is_none() {
+                self.deployed_files.remove(&op.path);
+                continue;
+            }
+            let working_file = self.working_dir.join(&op.path[1..]);
+            match FileStamp::of(&working_file) {
+                Ok(Some(stamp)) => {
+                    self.deployed_files.insert(op.path.clone(), stamp);
+                }
+                Ok(None) => {
+                    self.deployed_files.remove(&op.path);
+                }
+                Err(e) => {
+                    warn!("Couldn't read {}, changes to it won't be noticed: {}", working_file.display(), e);
+                    self.deployed_files.remove(&op.path);
+                }
+            }
+        }
+    }
+
+    /// Find deployed files that were removed, replaced or edited since they were deployed, for example by
+    /// the game writing to a file it was given through a hard link, which edits the file in the mod too.
+    ///
+    /// Deploying refuses to go ahead while there are any, with [`ModError::DeployedFilesChanged`]; each has
+    /// to be dealt with using [`ModManager::resolve_change`] first.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// for change in manager.verify().unwrap() {
+    ///     println!("{}: {:?}", change.path, change.kind);
+    /// }
+    /// ```
+    pub fn verify(&self) -> Result<Vec<ChangedFile>, ModError> {
+        let mut files = Vec::new();
+        self.current_active_tree.files("", &mut files);
+        files.sort();
+        let mut changes = Vec::new();
+        for (path, source) in files {
+            if let Some(kind) = self.check_deployed_file(&path, source)? {
+                changes.push(ChangedFile { path, source, kind });
+            }
+        }
+        Ok(changes)
+    }
+
+    fn check_deployed_file(&self, path: &str, source: Uuid) -> Result<Option<ChangeKind>, ModError> {
+        let Some(stamp) = self.deployed_files.get(path) else {
+            return Ok(None);
+        };
+        let working_file = self.working_dir.join(&path[1..]);
+        let now = FileStamp::of(&working_file).at(&working_file)?;
+        Ok(match stamp.compare(now.as_ref()) {
+            Some(ChangeKind::Edited { .. }) => Some(ChangeKind::Edited {
+                source_modified: self
+                    .mod_by_uuid(source)
+                    .is_ok_and(|source| deploy::is_deployed(&working_file, &source.file_path(&path[1..]), false)),
+            }),
+            kind => kind,
+        })
+    }
+
+    /// Deal with a deployed file found to be changed by [`ModManager::verify`], by restoring the mod file
+    /// or by moving the changed file into another mod, such as the overwrite mod. Either way the mod file
+    /// is deployed again afterwards.
+    ///
+    /// `path` is relative to the working directory, with or without a leading `/`. If the file is no longer
+    /// changed, nothing is done.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::{ModManager, Resolution};
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let overwrite = manager.overwrite_mod();
+    /// for change in manager.verify().unwrap() {
+    ///     manager.resolve_change(&change.path, Resolution::MoveTo(overwrite)).unwrap();
+    /// }
+    /// ```
+    pub fn resolve_change(&mut self, path: &str, resolution: Resolution) -> Result<(), ModError> {
+        let path = &normalize_path(path).ok_or_else(|| ModError::NotDeployed(path.to_string()))?;
+        let source = self
+            .current_active_tree
+            .file_source(path)
+            .ok_or_else(|| ModError::NotDeployed(path.to_string()))?;
+        let Some(kind) = self.check_deployed_file(path, source)? else {
+            info!("{} is unchanged", path);
+            return Ok(());
+        };
+        let working_file = self.working_dir.join(&path[1..]);
+        match resolution {
+            Resolution::Restore => {
+                if kind == (ChangeKind::Edited { source_modified: true }) {
+                    return Err(ModError::OriginalLost(path.to_string()));
+                }
+                info!("Restoring {}", working_file.display());
+            }
+            Resolution::MoveTo(GENERATED_MOD) => return Err(ModError::BuiltInMod(GENERATED_MOD)),
+            Resolution::MoveTo(target) => {
+                let target_file = self.mod_by_uuid(target)?.file_path(&path[1..]);
+                info!("Moving {} to {}", working_file.display(), target_file.display());
+                if !deploy::is_deployed(&target_file, &working_file, false) {
+                    copy_replacing(&working_file, &target_file)?;
+                }
+                fs::remove_file(&working_file).at(&working_file)?;
+                let key = self.hash_map[&target];
+                self.reload_mod(key, self.slotmap[key].settings.clone())?;
+            }
+        }
+        let op = Operation {
+            kind: OperationKind::ChangeSource { from: source, to: source },
+            path: path.to_string(),
+        };
+        self.apply_operation(&op, false)?;
+        self.stamp_files(&[op]);
+        self.save_state()
+    }
+
+    /// Report every file provided by more than one active mod and which mod wins it under the current
+    /// order, along with per mod totals.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let mod1 = manager.add_mod("./mod1".into()).unwrap();
+    /// let mod2 = manager.add_mod("./mod2".into()).unwrap();
+    /// manager.activate_mod(mod1).unwrap();
+    /// manager.activate_mod(mod2).unwrap();
+    /// for conflict in manager.conflicts().files {
+    ///     println!("{}: {:?} -> {}", conflict.path, conflict.providers, conflict.winner);
+    /// }
+    /// ```
+    pub fn conflicts(&self) -> ConflictReport {
+        let mut overwrites = Vec::new();
+        self.make_tree(&mut overwrites);
+        let order: Vec<Uuid> = std::iter::once(OVERWRITE_MOD)
+            .chain(self.active_mods.iter().map(|&key| self.slotmap[key].metadata.uuid))
+            .collect();
+        ConflictReport::new(&order, overwrites)
+    }
+
+    /// Check the `requires` and `conflicts_with` relations of every active mod against the other active
+    /// mods. Optional mods whose version doesn't match are only logged. Active mods that couldn't be
+    /// loaded are reported as well, see [`ModManager::mod_load_error`].
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// let mod1 = manager.add_mod("./mod1".into()).unwrap();
+    /// manager.activate_mod(mod1).unwrap();
+    /// for problem in manager.dependency_problems() {
+    ///     println!("{}", problem);
+    /// }
+    /// ```
+    pub fn dependency_problems(&self) -> Vec<ModError> {
+        let active = |uuid: Uuid| {
+            self.active_mods
+                .iter()
+                .map(|&key| &self.slotmap[key].metadata)
+                .find(|metadata| metadata.uuid == uuid)
+        };
+        let mut problems = Vec::new();
+        for r#mod in self.active_mods.iter().map(|&key| &self.slotmap[key]) {
+            if let Some(reason) = &r#mod.load_error {
+                problems.push(ModError::ModNotLoaded {
+                    uuid: r#mod.metadata.uuid,
+                    dir: r#mod.dir.clone(),
+                    reason: reason.clone(),
+                });
+            }
+        }
+        for metadata in self.active_mods.iter().map(|&key| &self.slotmap[key].metadata) {
+            for required in &metadata.requires {
+                match active(required.uuid) {
+                    None => problems.push(ModError::MissingDependency {
+                        uuid: metadata.uuid,
+                        name: metadata.name.clone(),
+                        required: required.uuid,
+                        version: required.version.clone(),
+                    }),
+                    Some(found) if !required.version.matches(&found.version) => {
+                        problems.push(ModError::DependencyVersionMismatch {
+                            uuid: metadata.uuid,
+                            name: metadata.name.clone(),
+                            required: required.uuid,
+                            version: required.version.clone(),
+                            found: found.version.clone(),
+                        })
+                    }
+                    Some(_) => {}
+                }
+            }
+            for incompatible in &metadata.conflicts_with {
+                if let Some(found) = active(incompatible.uuid).filter(|found| incompatible.version.matches(&found.version)) {
+                    problems.push(ModError::IncompatibleMods {
+                        uuid: metadata.uuid,
+                        name: metadata.name.clone(),
+                        other: found.uuid,
+                        other_name: found.name.clone(),
+                        version: incompatible.version.clone(),
+                    });
+                }
+            }
+            for optional in &metadata.optional {
+                if let Some(found) = active(optional.uuid).filter(|found| !optional.version.matches(&found.version)) {
+                    warn!(
+                        "Mod {} works with mod {} {}, but version {} is active",
+                        metadata.name, found.name, optional.version, found.version
+                    );
+                }
+            }
+        }
+        problems
+    }
+
+    /// The uuid of the built-in overwrite mod. It is always active and always loads last, so its files win
+    /// over those of every other mod. [`ModManager::capture_new_files`] puts files into it.
+    pub fn overwrite_mod(&self) -> Uuid {
+        OVERWRITE_MOD
+    }
+
+    /// The directory a registered mod was loaded from.
+    pub fn mod_dir(&self, uuid: Uuid) -> Option<&Path> {
+        self.mod_by_uuid(uuid).ok().map(|r#mod| r#mod.dir.as_path())
+    }
+
+    /// Move files the game or other tools created or changed in the working directory into the overwrite
+    /// mod, and deploy them from there. Vanilla files are left alone, even if they were changed, and so
+    /// are deployed files that are unchanged. Returns the paths of the captured files.
+    ///
+    /// Once captured, the files can be managed like those of any mod: moved into another one or deleted
+    /// from the overwrite mod's directory, see [`ModManager::mod_dir`].
+    ///
+    /// New files look the same whether the game wrote them or a game update installed them. If vanilla
+    /// files were changed as well, see [`ModManager::vanilla_changes`], the game may have been updated, and
+    /// this fails with [`ModError::GameUpdated`] instead of moving the new files out of the game. Either
+    /// accept them as vanilla files with [`ModManager::refresh_vanilla`], or move them out by hand.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use modulate_lib::ModManager;
+    /// let mut manager = ModManager::new("./working_dir".parse().unwrap(), "./bak".parse().unwrap()).unwrap();
+    /// for path in manager.capture_new_files().unwrap() {
+    ///     println!("captured {}", path);
+    /// }
+    /// ```
+    pub fn capture_new_files(&mut self) -> Result<Vec<String>, ModError> {
+        let mut captured = Vec::new();
+        let result = self.capture_files(&mut captured);
+        // whatever was captured before a failure is still in the overwrite mod and deployed
+        let key = self.hash_map[&OVERWRITE_MOD];
+        self.reload_mod(key, self.slotmap[key].settings.clone())?;
+        self.stamp_files(&captured);
+        self.save_state()?;
+        result.map(|()| captured.into_iter().map(|op| op.path).collect())
+    }
+
+    fn capture_files(&mut self, captured: &mut Vec<Operation>) -> Result<(), ModError> {
+        let (added, updated): (Vec<VanillaChange>, Vec<VanillaChange>) = self
+            .vanilla_changes()?
+            .into_iter()
+            .partition(|change| change.kind == VanillaChangeKind::Added);
+        if !added.is_empty() && !updated.is_empty() {
+            return Err(ModError::GameUpdated(added.into_iter().map(|change| change.path).collect()));
+        }
+        let overwrite_dir = self.mod_by_uuid(OVERWRITE_MOD)?.dir.clone();
+        let mut files = Vec::new();
+        walk_files(&self.working_dir, "", &mut files)?;
+        for path in files {
+            let working_file = self.working_dir.join(&path[1..]);
+            let overwrite_file = overwrite_dir.join(&path[1..]);
+            let kind = match self.current_active_tree.file_source(&path) {
+                Some(source) => {
+                    match self.check_deployed_file(&path, source)? {
+                        None | Some(ChangeKind::Removed) => continue,
+                        Some(ChangeKind::Edited { source_modified: true }) if source != OVERWRITE_MOD => {
+                            warn!("{} was edited in place, so was the file of mod {}", path, source)
+                        }
+                        Some(_) => {}
+                    }
+                    if !deploy::is_deployed(&overwrite_file, &working_file, false) {
+                        copy_replacing(&working_file, &overwrite_file)?;
+                    }
+                    OperationKind::ChangeSource {
+                        from: source,
+                        to: OVERWRITE_MOD,
+                    }
+                }
+                None if self.baseline.files.contains_key(&path) => continue,
+                None => {
+                    create_parent_dir(&overwrite_file).at(&overwrite_file)?;
+                    move_file(&working_file, &overwrite_file).at(&working_file)?;
+                    OperationKind::CreateFile(OVERWRITE_MOD)
+                }
+            };
+            info!("Capturing {} into the overwrite mod", path);
+            let op = Operation { kind, path };
+            self.apply_operation(&op, false)?;
+            self.current_active_tree.set_file_source(&op.path, OVERWRITE_MOD, None);
+            captured.push(op);
+        }
+        Ok(())
+    }
+
+    /// Look up a registered mod by uuid.
+    pub fn mod_metadata(&self, uuid: Uuid) -> Option<&ModMetadata> {
+        self.mod_by_uuid(uuid).ok().map(|r#mod| &r#mod.metadata)
+    }
+
+    /// Why the registered mod with `uuid` couldn't be loaded, if it couldn't, for example because its
+    /// directory was moved. It is loaded again by the next [`ModManager::new`], and can't be deployed until
+    /// then.
+    pub fn mod_load_error(&self, uuid: Uuid) -> Option<&str> {
+        self.mod_by_uuid(uuid).ok()?.load_error.as_deref()
+    }
+
+    fn make_tree(&self, overwrites: &mut Vec<Overwrite>) -> SourcedNode {
+        let mut tree = SourcedNode::Dir {
+            name: "root".to_string(),
+            children: HashMap::new(),
+        };
+        info!("Calculating virtual tree");
+        for key in self.active_mods.iter().rev() {
+            trace!(" - Adding mod: {}", self.slotmap[*key].metadata.name);
+            let r#mod = &self.slotmap[*key];
+            tree.overwrite_with(&r#mod.node, r#mod.metadata.uuid, "", overwrites, self.case_insensitive);
+        }
+        // the overwrite mod always loads last
+        let overwrite = &self.slotmap[self.hash_map[&OVERWRITE_MOD]];
+        tree.overwrite_with(&overwrite.node, OVERWRITE_MOD, "", overwrites, self.case_insensitive);
+        if self.case_insensitive {
+            tree.match_case(&[self.working_dir.clone(), self.bak_dir.clone()]);
+            // the overwrites were recorded with the spellings of the mods
+            for overwrite in overwrites.iter_mut() {
+                overwrite.path = tree.spelling_of(&overwrite.path);
+            }
+        }
+        tree
+    }
+
+    /// Apply `ops` in order from `start` on. If one fails, it and the ones before it are rolled back.
+    fn apply_operations(
+        &self,
+        ops: &[Operation],
+        start: usize,
+        resuming: bool,
+        journal: &mut JournalFile,
+    ) -> Result<(), ModError> {
+        for (i, op) in ops.iter().enumerate().skip(start) {
+            let recovering = resuming && i == start;
+            if let Err(e) = self.apply_operation(op, recovering).and_then(|_| journal.applied(i)) {
+                error!("{}", e);
+                return Err(self.roll_back(&ops[..=i], journal, e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo `ops` in reverse order. The last one may have only been partially applied when it failed, so
+    /// the inverses are applied in recovering mode, which copes with half-done operations.
+    fn roll_back(&self, ops: &[Operation], journal: &mut JournalFile, cause: ModError) -> ModError {
+        warn!("Rolling back {} operations", ops.len());
+        let mut rolled_back = Vec::new();
+        for (i, op) in ops.iter().enumerate().rev() {
+            info!("Rolling back: {}", op);
+            if let Err(e) = self
+                .apply_operation(&op.inverse(), true)
+                .and_then(|_| journal.rolled_back(i))
+            {
+                error!("Rollback failed: {}", e);
+                return ModError::RollbackFailed {
+                    source: Box::new(cause),
+                    rollback_error: Box::new(e),
+                    rolled_back,
+                    not_rolled_back: ops[..=i].to_vec(),
+                };
+            }
+            rolled_back.push(op.clone());
+        }
+        ModError::DeployRolledBack {
+            source: Box::new(cause),
+            rolled_back,
+        }
+    }
+
+    /// Apply a single operation.
+    ///
+    /// With `recovering` set, the operation may already have been partially or fully applied (by a deploy
+    /// that failed or was interrupted), so files are only removed if they are provably the deployed ones.
+    fn apply_operation(&self, op: &Operation, recovering: bool) -> Result<(), ModError> {
+        let path = &op.path[1..];
+        let working_file = self.working_dir.join(path);
+        let back_file = self.bak_dir.join(path);
+
+        match op.kind {
+            OperationKind::CreateDir => {
+                info!("Creating dir: {}", working_file.display());
+                // a game file where a mod has a dir is backed up like a file a mod replaces
+                if exists(&working_file) && !working_file.is_dir() {
+                    if !backup_exists(&back_file).during(op, &back_file)? {
+                        trace!(" - Creating backup: {}", back_file.display());
+                        create_parent_dir(&back_file).during(op, &back_file)?;
+                        fs::rename(&working_file, &back_file).during(op, &back_file)?;
+                    } else {
+                        trace!(" - Removing file: {}", working_file.display());
+                        fs::remove_file(&working_file).during(op, &working_file)?;
+                    }
+                }
+                fs::create_dir_all(&working_file).during(op, &working_file)?;
+            }
+            OperationKind::RemoveDir => {
+                if self.baseline.dirs.contains(&op.path) {
+                    trace!(" - Keeping vanilla dir: {}", working_file.display());
+                } else if working_file.is_dir() && working_file.read_dir().during(op, &working_file)?.next().is_none() {
+                    info!("Removing dir: {}", working_file.display());
+                    fs::remove_dir(&working_file).during(op, &working_file)?;
+                }
+                if !exists(&working_file) && fs::symlink_metadata(&back_file).is_ok_and(|metadata| !metadata.is_dir()) {
+                    trace!(" - Restoring backup: {} -> {}", back_file.display(), working_file.display());
+                    fs::rename(&back_file, &working_file).during(op, &back_file)?;
+                }
+            }
+            OperationKind::CreateFile(source) => {
+                let strategy = self.strategy_for(source);
+                let source = self.mod_by_uuid(source)?;
+                let mod_file = source.file_path(path);
+                info!("Creating file with {}: {} -> {} ({})", strategy, mod_file.display(), working_file.display(), source.metadata.name);
+                // check if file exists
+                if exists(&working_file) {
+                    if deploy::is_deployed(&working_file, &mod_file, recovering) {
+                        trace!(" - Already deployed");
+                        return Ok(());
+                    }
+                    if !backup_exists(&back_file).during(op, &back_file)? {
+                        trace!(" - Creating backup: {}", back_file.display());
+                        create_parent_dir(&back_file).during(op, &back_file)?;
+                        fs::rename(&working_file, &back_file).during(op, &back_file)?;
+                    } else {
+                        trace!(" - Removing file: {}", working_file.display());
+                        fs::remove_file(&working_file).during(op, &working_file)?;
+                    }
+                }
+                create_parent_dir(&working_file).during(op, &working_file)?;
+                strategy.deploy(&mod_file, &working_file).during(op, &mod_file)?;
+            }
+            OperationKind::RemoveFile(source) => {
+                info!("Removing file: {}", working_file.display());
+                if exists(&working_file) {
+                    let deployed = !recovering
+                        || self
+                            .mod_by_uuid(source)
+                            .is_ok_and(|source| deploy::is_deployed(&working_file, &source.file_path(path), true));
+                    if deployed {
+                        fs::remove_file(&working_file).during(op, &working_file)?;
+                    } else {
+                        trace!(" - Keeping file, it isn't the deployed one");
+                    }
+                }
+                if exists(&back_file) {
+                    if exists(&working_file) {
+                        warn!("Not restoring backup {}, {} is in the way", back_file.display(), working_file.display());
+                    } else {
+                        trace!(" - Restoring backup: {} -> {}", back_file.display(), working_file.display());
+                        fs::rename(&back_file, &working_file).during(op, &back_file)?;
+                    }
+                }
+            }
+            OperationKind::ChangeSource { to, .. } => {
+                let strategy = self.strategy_for(to);
+                let new_source = self.mod_by_uuid(to)?;
+                info!("Changing source with {}: {} ({})", strategy, working_file.display(), new_source.metadata.name);
+                let mod_file = new_source.file_path(path);
+                if exists(&working_file) {
+                    if deploy::is_deployed(&working_file, &mod_file, recovering) {
+                        trace!(" - Already deployed");
+                        return Ok(());
+                    }
+                    trace!(" - Removing file: {}", working_file.display());
+                    fs::remove_file(&working_file).during(op, &working_file)?;
+                }
+                create_parent_dir(&working_file).during(op, &working_file)?;
+                strategy.deploy(&mod_file, &working_file).during(op, &mod_file)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn strategy_for(&self, uuid: Uuid) -> DeployStrategy {
+        self.mod_strategies.get(&uuid).copied().unwrap_or(self.deploy_strategy)
+    }
+
+    fn mod_by_uuid(&self, uuid: Uuid) -> Result<&Mod, ModError> {
+        self.hash_map
+            .get(&uuid)
+            .map(|&key| &self.slotmap[key])
+            .ok_or(ModError::InvalidModUuid(uuid))
+    }
+
+    pub fn print_tree(&self) {
+        self.current_active_tree.print(0);
+    }
+}
+
+fn changed_paths(changes: &[ChangedFile]) -> String {
+    changes.iter().map(|change| change.path.as_str()).collect::<Vec<_>>().join(", ")
+}
+
+/// A file next to `dir`, named after it: `sibling_path("/games/bak", "state")` is `/games/bak.state`.
+fn sibling_path(dir: &Path, extension: &str) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(extension);
+    dir.with_file_name(name)
+}
+
+/// Whether both paths are the same file on disk, i.e. hard links to one another.
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::symlink_metadata(a), fs::symlink_metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Whether anything is at `path`, without following symlinks, so that a symlink to a mod file that is gone
+/// still counts.
+fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+/// Whether there is a backup at `back_file`. An empty dir left behind by restoring the backups that were
+/// in it doesn't count, and is removed to make room for a new backup.
+fn backup_exists(back_file: &Path) -> io::Result<bool> {
+    match fs::symlink_metadata(back_file) {
+        Ok(metadata) if metadata.is_dir() && fs::read_dir(back_file)?.next().is_none() => {
+            fs::remove_dir(back_file)?;
+            Ok(false)
+        }
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Rename `from` to `to`, or copy it and remove the original if they are on different filesystems.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Remove every directory below `dir` that is empty or only holds empty directories, keeping `dir` itself.
+fn remove_empty_dirs(dir: &Path) -> Result<(), ModError> {
+    for entry in fs::read_dir(dir).at(dir)? {
+        let path = entry.at(dir)?.path();
+        if fs::symlink_metadata(&path).at(&path)?.is_dir() {
+            remove_empty_dirs(&path)?;
+            if fs::read_dir(&path).at(&path)?.next().is_none() {
+                fs::remove_dir(&path).at(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `path`, relative to the working directory, in the form of the paths of operations: starting with a `/`,
+/// with `/` separators and without empty or `.` components. `None` if it leaves the working directory.
+fn normalize_path(path: &str) -> Option<String> {
+    let mut normalized = String::new();
+    for name in path.split('/').filter(|&name| !name.is_empty() && name != ".") {
+        if name == ".." {
+            return None;
+        }
+        normalized.push('/');
+        normalized.push_str(name);
+    }
+    Some(normalized)
+}
+
+/// Copy the file at `from` to `to`, replacing whatever file is there. A copy, not a rename: a file in the
+/// working directory may be a hard link to the file of a mod, which has to stay as it is.
+fn copy_replacing(from: &Path, to: &Path) -> Result<(), ModError> {
+    create_parent_dir(to).at(to)?;
+    if exists(to) {
+        fs::remove_file(to).at(to)?;
+    }
+    fs::copy(from, to).at(from)?;
+    Ok(())
+}
+
+fn create_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+/// Deserialize bincode from a file of `len` bytes, refusing to allocate more than the file could possibly
+/// hold so that a corrupt file turns into an error instead of an allocation failure.
+fn deserialize_limited<T: serde::de::DeserializeOwned>(reader: impl io::Read, len: u64) -> Result<T, bincode::Error> {
+    use bincode::Options;
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(len)
+        .deserialize_from(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::collections::BTreeMap;
+    use std::io::Write;
+
+    impl TempDir {
+        /// A scratch dir with an empty game dir in it.
+        fn with_game(name: &str) -> Self {
+            let tmp = TempDir::new(name);
+            fs::create_dir(tmp.game()).unwrap();
+            tmp
+        }
+
+        fn game(&self) -> PathBuf {
+            self.path().join("game")
+        }
+
+        /// A manager for the game dir, as if the process was started again.
+        fn manager(&self) -> ModManager {
+            ModManager::new(self.game(), self.path().join("bak")).unwrap()
+        }
+
+        /// Write a mod named `name` with `files`, and `metadata` added to its `mod.toml`.
+        fn write_mod(&self, name: &str, uuid: u128, metadata: &str, files: &[(&str, &str)]) -> PathBuf {
+            let dir = self.path().join(name);
+            let uuid = Uuid::from_u128(uuid);
+            let toml = format!("name = \"{}\"\nversion = \"1.0.0\"\nuuid = \"{}\"\n{}", name, uuid, metadata);
+            write(&dir.join("mod.toml"), &toml);
+            for (path, contents) in files {
+                write(&dir.join(path), contents);
+            }
+            dir
+        }
+    }
+
+    fn write(path: &Path, contents: &str) {
+        create_parent_dir(path).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Every file below `dir` with its contents, and every dir with `None`.
+    fn snapshot(dir: &Path) -> BTreeMap<String, Option<String>> {
+        let (mut files, mut dirs) = (Vec::new(), Vec::new());
+        walk(dir, "", &mut files, &mut dirs).unwrap();
+        let files = files.into_iter().map(|path| {
+            let contents = fs::read_to_string(dir.join(&path[1..])).unwrap();
+            (path, Some(contents))
+        });
+        files.chain(dirs.into_iter().map(|path| (path, None))).collect()
+    }
+
+    /// A game with a vanilla file and an empty vanilla dir, and a mod that replaces the file and deploys
+    /// into the dir and into dirs of its own.
+    fn game_with_mod(tmp: &TempDir) -> (ModManager, Uuid, PathBuf) {
+        write(&tmp.game().join("a.txt"), "vanilla");
+        fs::create_dir(tmp.game().join("Saves")).unwrap();
+        let files = [
+            ("a.txt", "mod"),
+            ("Saves/s.txt", "save"),
+            ("new/b.txt", "b"),
+            ("new/deep/c.txt", "c"),
+        ];
+        let dir = tmp.write_mod("mod", 1, "", &files);
+        let mut manager = tmp.manager();
+        let uuid = manager.add_mod(dir.clone()).unwrap();
+        manager.activate_mod(uuid).unwrap();
+        (manager, uuid, dir)
+    }
+
+    fn active_uuids(manager: &ModManager) -> Vec<Uuid> {
+        manager.active_mods().iter().map(|metadata| metadata.uuid).collect()
+    }
+
+    /// Deploy a mod with `files`, `metadata` and the ignore rules `ignore` into an empty game, and return
+    /// the files it deployed.
+    fn deployed_files(tmp: &TempDir, metadata: &str, files: &[(&str, &str)], ignore: &[&str]) -> Vec<String> {
+        let dir = tmp.write_mod("mod", 1, metadata, files);
+        let mut manager = tmp.manager();
+        let uuid = manager.add_mod(dir).unwrap();
+        manager.set_mod_ignore_rules(uuid, ignore.iter().map(|rule| rule.to_string()).collect()).unwrap();
+        manager.activate_mod(uuid).unwrap();
+        manager.deploy_mods().unwrap();
+        let mut deployed = Vec::new();
+        walk_files(&tmp.game(), "", &mut deployed).unwrap();
+        deployed
+    }
+
+    /// Start the deploy `plan` like [`ModManager::apply_plan`] does, but stop after `applied` operations
+    /// as if the process was killed there.
+    fn interrupt(manager: &mut ModManager, plan: DeployPlan, applied: usize) -> (JournalFile, Vec<Operation>) {
+        let ops: Vec<Operation> = plan.operations.into_iter().map(|step| step.operation).collect();
+        manager.record_vanilla_dirs(&ops).unwrap();
+        let journal = Journal {
+            new_tree: plan.new_tree,
+            ops: ops.clone(),
+        };
+        let mut journal_file = JournalFile::create(&manager.journal_path, &journal).unwrap();
+        for (i, op) in ops.iter().enumerate().take(applied) {
+            manager.apply_operation(op, false).unwrap();
+            journal_file.applied(i).unwrap();
+        }
+        (journal_file, ops)
+    }
+
+    #[test]
+    fn failed_deploy_is_rolled_back() {
+        let tmp = TempDir::with_game("rollback");
+        let (mut manager, _, dir) = game_with_mod(&tmp);
+        let before = snapshot(&tmp.game());
+        let plan = manager.plan_deploy().unwrap();
+        // the last file can only be deployed after its dirs
+        fs::remove_file(dir.join("new/deep/c.txt")).unwrap();
+        match manager.apply_plan(plan) {
+            Err(ModError::DeployRolledBack { rolled_back, .. }) => assert!(!rolled_back.is_empty()),
+            result => panic!("deploy wasn't rolled back: {:?}", result),
+        }
+        assert_eq!(snapshot(&tmp.game()), before);
+        assert!(!manager.journal_path.exists());
+
+        // nothing is left half deployed for the next deploy to trip over
+        write(&dir.join("new/deep/c.txt"), "c");
+        manager.deploy_mods().unwrap();
+        assert_eq!(fs::read_to_string(tmp.game().join("a.txt")).unwrap(), "mod");
+        assert_eq!(fs::read_to_string(tmp.game().join("new/deep/c.txt")).unwrap(), "c");
+    }
+
+    #[test]
+    fn interrupted_deploy_is_finished_on_startup() {
+        let tmp = TempDir::with_game("finish");
+        let (mut manager, _, _) = game_with_mod(&tmp);
+        let plan = manager.plan_deploy().unwrap();
+        let (_, ops) = interrupt(&mut manager, plan, 2);
+        drop(manager);
+
+        let mut manager = tmp.manager();
+        match manager.recovery() {
+            Some(Recovery::Finished(finished)) => assert_eq!(finished.len(), ops.len() - 2),
+            recovery => panic!("deploy wasn't finished: {:?}", recovery),
+        }
+        assert!(!manager.journal_path.exists());
+        let deployed = snapshot(&tmp.game());
+        assert_eq!(deployed["/a.txt"].as_deref(), Some("mod"));
+        assert_eq!(deployed["/Saves/s.txt"].as_deref(), Some("save"));
+        assert_eq!(deployed["/new/deep/c.txt"].as_deref(), Some("c"));
+        assert!(manager.plan_deploy().unwrap().is_empty());
+
+        // the finished deploy is undone like any other
+        manager.purge().unwrap();
+        let purged = snapshot(&tmp.game());
+        assert_eq!(purged.keys().collect::<Vec<_>>(), ["/Saves", "/a.txt"]);
+        assert_eq!(purged["/a.txt"].as_deref(), Some("vanilla"));
+    }
+
+    #[test]
+    fn interrupted_rollback_is_finished_on_startup() {
+        let tmp = TempDir::with_game("resume-rollback");
+        let (mut manager, _, _) = game_with_mod(&tmp);
+        let before = snapshot(&tmp.game());
+        let plan = manager.plan_deploy().unwrap();
+        let (mut journal_file, ops) = interrupt(&mut manager, plan, 3);
+        // the deploy failed, and the rollback undid one operation before it was killed as well
+        manager.apply_operation(&ops[2].inverse(), true).unwrap();
+        journal_file.rolled_back(2).unwrap();
+        drop((journal_file, manager));
+
+        let manager = tmp.manager();
+        match manager.recovery() {
+            Some(Recovery::RolledBack(rolled_back)) => assert_eq!(rolled_back.len(), 2),
+            recovery => panic!("deploy wasn't rolled back: {:?}", recovery),
+        }
+        assert!(!manager.journal_path.exists());
+        assert_eq!(snapshot(&tmp.game()), before);
+    }
+
+    #[test]
+    fn interrupted_deploy_that_cant_finish_is_rolled_back() {
+        let tmp = TempDir::with_game("recover-rollback");
+        let (mut manager, _, dir) = game_with_mod(&tmp);
+        let before = snapshot(&tmp.game());
+        let plan = manager.plan_deploy().unwrap();
+        drop(interrupt(&mut manager, plan, 2));
+        drop(manager);
+        fs::remove_file(dir.join("new/deep/c.txt")).unwrap();
+
+        let manager = tmp.manager();
+        assert!(matches!(manager.recovery(), Some(Recovery::RolledBack(_))), "{:?}", manager.recovery());
+        assert!(!manager.journal_path.exists());
+        assert_eq!(snapshot(&tmp.game()), before);
+    }
+
+    #[test]
+    fn normalizes_user_paths() {
+        assert_eq!(normalize_path("/a.ini").as_deref(), Some("/a.ini"));
+        assert_eq!(normalize_path("a.ini").as_deref(), Some("/a.ini"));
+        assert_eq!(normalize_path("é.ini").as_deref(), Some("/é.ini"));
+        assert_eq!(normalize_path("./data//b.txt/").as_deref(), Some("/data/b.txt"));
+        assert_eq!(normalize_path("data/../../b.txt"), None);
+    }
+
+    #[test]
+    fn excluded_dirs_deploy_nothing() {
+        let tmp = TempDir::with_game("exclude-dir");
+        let metadata = concat!(
+            "exclude = [\"extras\"]\n",
+            "[[options]]\nname = \"HD\"\nkind = \"single\"\n",
+            "choices = [{ name = \"HD\", dir = \"extras/hd\", default = true }]\n",
+        );
+        let files = [("a.txt", "a"), ("extras/readme.txt", "readme"), ("extras/hd/b.txt", "b")];
+        assert_eq!(deployed_files(&tmp, metadata, &files, &[]), ["/a.txt"]);
+    }
+
+    #[test]
+    fn ignore_rules_apply_to_option_dirs() {
+        let tmp = TempDir::with_game("ignore-option");
+        let metadata = concat!(
+            "[[options]]\nname = \"Textures\"\nkind = \"multi\"\n",
+            "choices = [{ name = \"4K\", dir = \"tex4k\", default = true }, ",
+            "{ name = \"Sounds\", dir = \"sounds\", default = true }]\n",
+        );
+        let files = [("a.txt", "a"), ("tex4k/b.dds", "b"), ("sounds/c.ogg", "c"), ("sounds/c.psd", "source")];
+        assert_eq!(deployed_files(&tmp, metadata, &files, &["tex4k", "*.psd"]), ["/a.txt", "/c.ogg"]);
+    }
+
+    #[test]
+    fn files_cant_be_mapped_to_the_root() {
+        let tmp = TempDir::with_game("map-root");
+        let files = [("opt/settings.ini", "settings"), ("opt/extra/b.txt", "b")];
+        let mapping = |from: &str| format!("[[mappings]]\nfrom = \"{}\"\nto = \"\"\n", from);
+        let dir = tmp.write_mod("file", 1, &mapping("opt/settings.ini"), &files);
+        let result = tmp.manager().add_mod(dir);
+        assert!(matches!(result, Err(ModError::InvalidModMetadata(_))), "{:?}", result);
+
+        assert_eq!(deployed_files(&tmp, &mapping("opt"), &files, &[]), ["/extra/b.txt", "/settings.ini"]);
+    }
+
+    #[test]
+    fn ignore_rules_apply_to_the_root_and_mapped_dirs() {
+        let tmp = TempDir::with_game("ignore-mapped");
+        let metadata = "root = \"Data\"\n[[mappings]]\nfrom = \"opt/settings.ini\"\nto = \"settings.ini\"\n";
+        let files = [("Data/a.txt", "a"), ("opt/settings.ini", "settings")];
+        assert_eq!(deployed_files(&tmp, metadata, &files, &["opt/"]), ["/a.txt"]);
+
+        let tmp = TempDir::with_game("ignore-root");
+        assert_eq!(deployed_files(&tmp, metadata, &files, &["Data"]), ["/settings.ini"]);
+    }
+
+    #[test]
+    fn capturing_new_files_keeps_the_vanilla_dirs() {
+        let tmp = TempDir::with_game("capture-purge");
+        let (mut manager, _, _) = game_with_mod(&tmp);
+        manager.deploy_mods().unwrap();
+        write(&tmp.game().join("new/save.dat"), "save");
+        assert_eq!(manager.capture_new_files().unwrap(), ["/new/save.dat"]);
+        assert_eq!(fs::read_to_string(tmp.game().join("new/save.dat")).unwrap(), "save");
+
+        manager.purge().unwrap();
+        let purged = snapshot(&tmp.game());
+        assert_eq!(purged.keys().collect::<Vec<_>>(), ["/Saves", "/a.txt"]);
+        assert_eq!(purged["/a.txt"].as_deref(), Some("vanilla"));
+    }
+
+    #[test]
+    fn state_survives_a_restart() {
+        let tmp = TempDir::with_game("state");
+        let (mut manager, first, _) = game_with_mod(&tmp);
+        let second = manager.add_mod(tmp.write_mod("second", 2, "", &[("b.txt", "b")])).unwrap();
+        let inactive = manager.add_mod(tmp.write_mod("inactive", 3, "", &[("c.txt", "c")])).unwrap();
+        manager.activate_mod(second).unwrap();
+        manager.move_before(second, first).unwrap();
+        manager.set_deploy_strategy(DeployStrategy::Copy).unwrap();
+        manager.set_mod_deploy_strategy(first, Some(DeployStrategy::AbsoluteSymlink)).unwrap();
+        manager.set_mod_ignore_rules(second, vec!["*.psd".to_string()]).unwrap();
+        manager.set_case_insensitive(true).unwrap();
+        let rule = LoadRule {
+            before: inactive,
+            after: second,
+        };
+        manager.add_load_rule(rule.clone()).unwrap();
+        manager.deploy_mods().unwrap();
+        drop(manager);
+
+        let manager = tmp.manager();
+        assert_eq!(active_uuids(&manager), [second, first]);
+        assert_eq!(manager.inactive_mods().iter().map(|metadata| metadata.uuid).collect::<Vec<_>>(), [inactive]);
+        assert_eq!(manager.deploy_strategy(), DeployStrategy::Copy);
+        assert_eq!(manager.mod_deploy_strategy(first), Some(DeployStrategy::AbsoluteSymlink));
+        assert_eq!(manager.mod_deploy_strategy(second), None);
+        assert_eq!(manager.mod_ignore_rules(second).unwrap(), ["*.psd"]);
+        assert!(manager.case_insensitive());
+        assert_eq!(manager.load_rules(), [rule]);
+        // the deployed tree and stamps are restored, so nothing is left to deploy or looks changed
+        assert!(manager.plan_deploy().unwrap().is_empty());
+        assert!(manager.verify().unwrap().is_empty());
+    }
+
+    #[test]
+    fn mods_that_fail_to_load_are_kept() {
+        let tmp = TempDir::with_game("state-unloadable");
+        let (manager, uuid, dir) = game_with_mod(&tmp);
+        drop(manager);
+        let moved = tmp.path().join("moved");
+        fs::rename(&dir, &moved).unwrap();
+
+        let manager = tmp.manager();
+        assert_eq!(active_uuids(&manager), [uuid]);
+        assert!(manager.mod_load_error(uuid).is_some());
+        let problems = manager.dependency_problems();
+        assert!(matches!(problems[..], [ModError::ModNotLoaded { uuid: u, .. }] if u == uuid), "{:?}", problems);
+        assert!(manager.plan_deploy().is_err());
+        manager.save_state().unwrap();
+        drop(manager);
+
+        fs::rename(&moved, &dir).unwrap();
+        let mut manager = tmp.manager();
+        assert_eq!(active_uuids(&manager), [uuid]);
+        assert_eq!(manager.mod_load_error(uuid), None);
+        manager.deploy_mods().unwrap();
+        assert_eq!(fs::read_to_string(tmp.game().join("a.txt")).unwrap(), "mod");
+    }
+
+    #[test]
+    fn reports_dependency_problems() {
+        let tmp = TempDir::with_game("dependencies");
+        let relation = |kind: &str, uuid: u128, version: &str| {
+            format!("[[{}]]\nuuid = \"{}\"\nversion = \"{}\"\n", kind, Uuid::from_u128(uuid), version)
+        };
+        let mut manager = tmp.manager();
+        let mut add = |name: &str, uuid: u128, metadata: &str| {
+            manager.add_mod(tmp.write_mod(name, uuid, metadata, &[])).unwrap()
+        };
+        let needs_new = add("needs-new", 1, &relation("requires", 2, ">=2"));
+        let library = add("library", 2, "");
+        let needs_any = add("needs-any", 3, &relation("requires", 2, "*"));
+        let incompatible = add("incompatible", 4, &relation("conflicts_with", 1, "^1"));
+        let optional = add("optional", 5, &relation("optional", 2, ">=2"));
+
+        // requirements that aren't active yet are only reported
+        manager.activate_mod(needs_new).unwrap();
+        manager.activate_mod(needs_any).unwrap();
+        let problems = manager.dependency_problems();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        let missing: Vec<_> = problems
+            .iter()
+            .map(|problem| match problem {
+                ModError::MissingDependency { uuid, required, .. } => (*uuid, *required),
+                problem => panic!("unexpected problem: {:?}", problem),
+            })
+            .collect();
+        assert_eq!(missing, [(needs_new, library), (needs_any, library)]);
+        assert!(matches!(manager.plan_deploy(), Err(ModError::MissingDependency { .. })));
+
+        manager.activate_mod(library).unwrap();
+        let problems = manager.dependency_problems();
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        match &problems[0] {
+            ModError::DependencyVersionMismatch { uuid, required, found, .. } => {
+                assert_eq!((*uuid, *required, found.to_string().as_str()), (needs_new, library, "1.0.0"));
+            }
+            problem => panic!("unexpected problem: {:?}", problem),
+        }
+
+        // incompatible mods can't be activated at all, whichever of the two declares it
+        match manager.activate_mod(incompatible) {
+            Err(ModError::IncompatibleMods { uuid, other, .. }) => assert_eq!((uuid, other), (incompatible, needs_new)),
+            result => panic!("incompatible mod was activated: {:?}", result),
+        }
+        assert!(!active_uuids(&manager).contains(&incompatible));
+
+        // an optional mod of the wrong version is only logged
+        manager.deactivate_mod(needs_new).unwrap();
+        manager.activate_mod(optional).unwrap();
+        assert!(manager.dependency_problems().is_empty());
+        manager.deploy_mods().unwrap();
+    }
+
+    /// A manager with the active mods `a`, `b` and `c`, in that order, and the inactive mod `d`.
+    fn ordered_mods(tmp: &TempDir) -> (ModManager, [Uuid; 4]) {
+        let mut manager = tmp.manager();
+        let uuids = [1, 2, 3, 4].map(|uuid| {
+            let dir = tmp.write_mod(&uuid.to_string(), uuid, "", &[]);
+            manager.add_mod(dir).unwrap()
+        });
+        for &uuid in &uuids[..3] {
+            manager.activate_mod(uuid).unwrap();
+        }
+        (manager, uuids)
+    }
+
+    #[test]
+    fn moves_mods_by_uuid() {
+        let tmp = TempDir::with_game("move");
+        let (mut manager, [a, b, c, _]) = ordered_mods(&tmp);
+        manager.move_mod(c, 0).unwrap();
+        assert_eq!(active_uuids(&manager), [c, a, b]);
+        // positions past the end move to the end
+        manager.move_mod(c, 99).unwrap();
+        assert_eq!(active_uuids(&manager), [a, b, c]);
+        manager.move_before(c, a).unwrap();
+        assert_eq!(active_uuids(&manager), [c, a, b]);
+        manager.move_after(c, b).unwrap();
+        assert_eq!(active_uuids(&manager), [a, b, c]);
+        manager.move_after(a, b).unwrap();
+        assert_eq!(active_uuids(&manager), [b, a, c]);
+        manager.move_before(c, a).unwrap();
+        assert_eq!(active_uuids(&manager), [b, c, a]);
+        // next to itself is where it already is
+        manager.move_before(c, c).unwrap();
+        manager.move_after(c, c).unwrap();
+        assert_eq!(active_uuids(&manager), [b, c, a]);
+    }
+
+    #[test]
+    fn moves_only_active_mods() {
+        let tmp = TempDir::with_game("move-invalid");
+        let (mut manager, [a, _, _, d]) = ordered_mods(&tmp);
+        let before = active_uuids(&manager);
+        let unknown = Uuid::from_u128(99);
+        let results = [
+            manager.move_mod(unknown, 0),
+            manager.move_before(unknown, a),
+            manager.move_after(a, unknown),
+            manager.move_before(unknown, unknown),
+        ];
+        for result in results {
+            assert!(matches!(result, Err(ModError::InvalidModUuid(uuid)) if uuid == unknown), "{:?}", result);
+        }
+        let results = [
+            manager.move_mod(d, 0),
+            manager.move_before(d, a),
+            manager.move_after(a, d),
+            manager.move_after(d, d),
+        ];
+        for result in results {
+            assert!(matches!(result, Err(ModError::ModNotActive(uuid)) if uuid == d), "{:?}", result);
+        }
+        assert_eq!(active_uuids(&manager), before);
+    }
+
+    #[test]
+    fn sets_the_order_of_every_active_mod() {
+        let tmp = TempDir::with_game("set-order");
+        let (mut manager, [a, b, c, d]) = ordered_mods(&tmp);
+        manager.set_order(&[c, a, b]).unwrap();
+        assert_eq!(active_uuids(&manager), [c, a, b]);
+
+        let unknown = Uuid::from_u128(99);
+        match manager.set_order(&[b, unknown, d, b, a]) {
+            Err(ModError::InvalidModUuidOrder {
+                unknown: u,
+                inactive,
+                missing,
+                duplicated,
+            }) => {
+                assert_eq!(u, [unknown]);
+                assert_eq!(inactive, [d]);
+                assert_eq!(missing, [c]);
+                assert_eq!(duplicated, [b]);
+            }
+            result => panic!("invalid order was accepted: {:?}", result),
+        }
+        assert!(manager.set_order(&[]).is_err());
+        assert_eq!(active_uuids(&manager), [c, a, b]);
+    }
+
+    #[test]
+    fn removing_an_installed_mod_deletes_it_from_the_library() {
+        let tmp = TempDir::with_game("remove-installed");
+        let archive = tmp.path().join("installed.tar");
+        let mut tar = tar::Builder::new(fs::File::create(&archive).unwrap());
+        let dir = tmp.write_mod("installed", 1, "", &[("a.txt", "a")]);
+        tar.append_dir_all("installed", &dir).unwrap();
+        tar.finish().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let added = tmp.write_mod("added", 2, "", &[("b.txt", "b")]);
+
+        let mut manager = tmp.manager();
+        let installed = manager.install_archive(archive).unwrap();
+        let library_dir = manager.mod_dir(installed).unwrap().to_path_buf();
+        assert!(library_dir.starts_with(manager.library_dir()));
+        let added = manager.add_mod(added.clone()).unwrap();
+        manager.remove_mod(installed).unwrap();
+        manager.remove_mod(added).unwrap();
+        assert!(!library_dir.exists());
+        // a mod the manager was only pointed at is left where it is
+        assert!(tmp.path().join("added/b.txt").exists());
+    }
+
+    #[test]
+    fn replaced_file_is_restored() {
+        let tmp = TempDir::with_game("restore-replaced");
+        let (mut manager, uuid, dir) = game_with_mod(&tmp);
+        manager.deploy_mods().unwrap();
+        fs::remove_file(tmp.game().join("a.txt")).unwrap();
+        write(&tmp.game().join("a.txt"), "replaced");
+        let changes = manager.verify().unwrap();
+        let replaced = ChangedFile {
+            path: "/a.txt".to_string(),
+            source: uuid,
+            kind: ChangeKind::Replaced,
+        };
+        assert_eq!(changes, [replaced]);
+        assert!(matches!(manager.deploy_mods(), Err(ModError::DeployedFilesChanged(_))));
+
+        manager.resolve_change("a.txt", Resolution::Restore).unwrap();
+        assert_eq!(fs::read_to_string(tmp.game().join("a.txt")).unwrap(), "mod");
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "mod");
+        assert!(manager.verify().unwrap().is_empty());
+        manager.deploy_mods().unwrap();
+    }
+
+    #[test]
+    fn file_edited_through_a_hard_link_cant_be_restored() {
+        let tmp = TempDir::with_game("restore-edited");
+        let (mut manager, uuid, dir) = game_with_mod(&tmp);
+        manager.deploy_mods().unwrap();
+        fs::write(tmp.game().join("a.txt"), "edited").unwrap();
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "edited");
+        let kind = ChangeKind::Edited { source_modified: true };
+        assert_eq!(manager.verify().unwrap()[0].kind, kind);
+        let result = manager.resolve_change("/a.txt", Resolution::Restore);
+        assert!(matches!(result, Err(ModError::OriginalLost(_))), "{:?}", result);
+
+        // the edit can still be kept, in another mod
+        let overwrite = manager.overwrite_mod();
+        manager.resolve_change("/a.txt", Resolution::MoveTo(overwrite)).unwrap();
+        let overwrite_dir = manager.mod_dir(overwrite).unwrap().to_path_buf();
+        assert_eq!(fs::read_to_string(overwrite_dir.join("a.txt")).unwrap(), "edited");
+        assert!(manager.verify().unwrap().is_empty());
+        manager.deploy_mods().unwrap();
+        assert_eq!(manager.current_active_tree.file_source("/a.txt"), Some(overwrite));
+        assert_eq!(manager.current_active_tree.file_source("/Saves/s.txt"), Some(uuid));
+    }
+
+    #[test]
+    fn copied_file_edited_in_place_is_moved_to_another_mod() {
+        let tmp = TempDir::with_game("move-edited");
+        let (mut manager, uuid, dir) = game_with_mod(&tmp);
+        manager.set_deploy_strategy(DeployStrategy::Copy).unwrap();
+        manager.deploy_mods().unwrap();
+        fs::OpenOptions::new().append(true).open(tmp.game().join("a.txt")).unwrap().write_all(b" edited").unwrap();
+        let changes = manager.verify().unwrap();
+        assert_eq!(changes[0].kind, ChangeKind::Edited { source_modified: false });
+        assert_eq!(changes[0].source, uuid);
+
+        let overwrite = manager.overwrite_mod();
+        manager.resolve_change("/a.txt", Resolution::MoveTo(overwrite)).unwrap();
+        let overwrite_dir = manager.mod_dir(overwrite).unwrap().to_path_buf();
+        assert_eq!(fs::read_to_string(overwrite_dir.join("a.txt")).unwrap(), "mod edited");
+        // the mod file is deployed again until the next deploy lets the overwrite mod win
+        assert_eq!(fs::read_to_string(tmp.game().join("a.txt")).unwrap(), "mod");
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "mod");
+        manager.deploy_mods().unwrap();
+        assert_eq!(fs::read_to_string(tmp.game().join("a.txt")).unwrap(), "mod edited");
+    }
+
+    #[test]
+    fn game_update_is_taken_as_the_new_vanilla_files() {
+        let tmp = TempDir::with_game("game-update");
+        write(&tmp.game().join("b.txt"), "vanilla b");
+        write(&tmp.game().join("c.txt"), "vanilla c");
+        let (mut manager, _, _) = game_with_mod(&tmp);
+        manager.deploy_mods().unwrap();
+        assert!(manager.vanilla_changes().unwrap().is_empty());
+
+        // the update replaces the file the mod deploys over as well as those it doesn't
+        fs::remove_file(tmp.game().join("a.txt")).unwrap();
+        write(&tmp.game().join("a.txt"), "vanilla 2");
+        write(&tmp.game().join("b.txt"), "vanilla b 2");
+        fs::remove_file(tmp.game().join("c.txt")).unwrap();
+        let kinds = |changes: Vec<VanillaChange>| -> Vec<(String, VanillaChangeKind)> {
+            changes.into_iter().map(|change| (change.path, change.kind)).collect()
+        };
+        let expected = [
+            ("/a.txt".to_string(), VanillaChangeKind::BackupStale),
+            ("/b.txt".to_string(), VanillaChangeKind::Modified),
+            ("/c.txt".to_string(), VanillaChangeKind::Removed),
+        ];
+        assert_eq!(kinds(manager.vanilla_changes().unwrap()), expected);
+        let result = manager.purge();
+        assert!(matches!(&result, Err(ModError::StaleBackups(stale)) if stale == &["/a.txt"]), "{:?}", result);
+
+        assert_eq!(kinds(manager.refresh_vanilla().unwrap()), expected);
+        assert!(manager.vanilla_changes().unwrap().is_empty());
+        assert!(manager.verify().unwrap().is_empty());
+        assert_eq!(fs::read_to_string(tmp.game().join("a.txt")).unwrap(), "mod");
+        drop(manager);
+
+        // the refreshed manifest is what the next manager starts from
+        let mut manager = tmp.manager();
+        assert!(manager.vanilla_changes().unwrap().is_empty());
+        manager.purge().unwrap();
+        let purged = snapshot(&tmp.game());
+        assert_eq!(purged.keys().collect::<Vec<_>>(), ["/Saves", "/a.txt", "/b.txt"]);
+        assert_eq!(purged["/a.txt"].as_deref(), Some("vanilla 2"));
+        assert_eq!(purged["/b.txt"].as_deref(), Some("vanilla b 2"));
+    }
+
+    #[test]
+    fn files_added_by_a_game_update_become_vanilla_files() {
+        let tmp = TempDir::with_game("game-update-added");
+        let (mut manager, _, _) = game_with_mod(&tmp);
+        manager.deploy_mods().unwrap();
+
+        write(&tmp.game().join("new/patch.pak"), "patch");
+        let changes = manager.vanilla_changes().unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].path.as_str(), changes[0].kind), ("/new/patch.pak", VanillaChangeKind::Added));
+
+        assert_eq!(manager.refresh_vanilla().unwrap().len(), 1);
+        assert!(manager.vanilla_changes().unwrap().is_empty());
+        assert!(manager.capture_new_files().unwrap().is_empty());
+
+        // the added file is a game file now, so purging leaves it in place
+        manager.purge().unwrap();
+        let purged = snapshot(&tmp.game());
+        assert_eq!(purged.keys().collect::<Vec<_>>(), ["/Saves", "/a.txt", "/new", "/new/patch.pak"]);
+        assert_eq!(purged["/new/patch.pak"].as_deref(), Some("patch"));
+    }
+
+    #[test]
+    fn capture_refuses_new_files_after_a_game_update() {
+        let tmp = TempDir::with_game("capture-game-update");
+        write(&tmp.game().join("b.txt"), "vanilla b");
+        let (mut manager, _, _) = game_with_mod(&tmp);
+        manager.deploy_mods().unwrap();
+
+        write(&tmp.game().join("b.txt"), "vanilla b 2");
+        write(&tmp.game().join("patch.pak"), "patch");
+        let result = manager.capture_new_files();
+        assert!(matches!(&result, Err(ModError::GameUpdated(added)) if added == &["/patch.pak"]), "{:?}", result);
+        assert_eq!(fs::read_to_string(tmp.game().join("patch.pak")).unwrap(), "patch");
+        assert!(!manager.mod_dir(OVERWRITE_MOD).unwrap().join("patch.pak").exists());
+
+        // once the update is accepted, only what the game writes afterwards is captured
+        manager.refresh_vanilla().unwrap();
+        write(&tmp.game().join("Saves/s2.txt"), "save");
+        assert_eq!(manager.capture_new_files().unwrap(), ["/Saves/s2.txt"]);
+        assert!(tmp.game().join("patch.pak").is_file());
+    }
+
+    #[test]
+    fn files_and_dirs_replace_each_other_between_deploys() {
+        let tmp = TempDir::with_game("type-change");
+        write(&tmp.game().join("data"), "vanilla");
+        let as_file = tmp.write_mod("file", 1, "", &[("data", "file")]);
+        let as_dir = tmp.write_mod("dir", 2, "", &[("data/x.txt", "x")]);
+        let mut manager = tmp.manager();
+        let as_file = manager.add_mod(as_file).unwrap();
+        let as_dir = manager.add_mod(as_dir).unwrap();
+        manager.activate_mod(as_file).unwrap();
+        manager.deploy_mods().unwrap();
+        assert_eq!(fs::read_to_string(tmp.game().join("data")).unwrap(), "file");
+
+        manager.activate_mod(as_dir).unwrap();
+        manager.move_before(as_dir, as_file).unwrap();
+        manager.deploy_mods().unwrap();
+        assert_eq!(fs::read_to_string(tmp.game().join("data/x.txt")).unwrap(), "x");
+
+        manager.deactivate_mod(as_dir).unwrap();
+        manager.deploy_mods().unwrap();
+        assert_eq!(fs::read_to_string(tmp.game().join("data")).unwrap(), "file");
+
+        // the vanilla file is restored from under the dir as well
+        manager.activate_mod(as_dir).unwrap();
+        manager.move_before(as_dir, as_file).unwrap();
+        manager.deploy_mods().unwrap();
+        manager.purge().unwrap();
+        let purged = snapshot(&tmp.game());
+        assert_eq!(purged.keys().collect::<Vec<_>>(), ["/data"]);
+        assert_eq!(purged["/data"].as_deref(), Some("vanilla"));
+    }
+
+    #[test]
+    fn case_insensitive_merge_uses_the_existing_spellings() {
+        let tmp = TempDir::with_game("match-case");
+        write(&tmp.game().join("Textures/x.dds"), "vanilla");
+        write(&tmp.path().join("bak/Data/A.txt"), "backup");
+        let mut tree = SourcedNode::Dir {
+            name: "root".to_string(),
+            children: HashMap::new(),
+        };
+        for path in ["/textures/X.DDS", "/data/a.txt", "/data/b.txt", "/new/c.txt"] {
+            tree.set_file_source(path, Uuid::from_u128(1), None);
+        }
+        tree.match_case(&[tmp.game(), tmp.path().join("bak")]);
+        let mut files = Vec::new();
+        tree.files("", &mut files);
+        let mut paths: Vec<String> = files.into_iter().map(|(path, _)| path).collect();
+        paths.sort();
+        assert_eq!(paths, ["/Data/A.txt", "/Data/b.txt", "/Textures/x.dds", "/new/c.txt"]);
+    }
+
+    #[test]
+    fn case_insensitive_deploys_merge_into_the_vanilla_spelling() {
+        let tmp = TempDir::with_game("case-insensitive");
+        write(&tmp.game().join("Data/a.txt"), "vanilla");
+        let first = tmp.write_mod("first", 1, "", &[("data/A.txt", "first")]);
+        let second = tmp.write_mod("second", 2, "", &[("DATA/a.TXT", "second"), ("DATA/b.txt", "b")]);
+        let mut manager = tmp.manager();
+        let first = manager.add_mod(first).unwrap();
+        let second = manager.add_mod(second).unwrap();
+        manager.activate_mod(first).unwrap();
+        manager.activate_mod(second).unwrap();
+        manager.set_case_insensitive(true).unwrap();
+        manager.deploy_mods().unwrap();
+        let deployed = snapshot(&tmp.game());
+        assert_eq!(deployed.keys().collect::<Vec<_>>(), ["/Data", "/Data/a.txt", "/Data/b.txt"]);
+        assert_eq!(deployed["/Data/a.txt"].as_deref(), Some("first"));
+        let conflicts = manager.conflicts();
+        assert_eq!(conflicts.files.len(), 1);
+        assert_eq!(conflicts.files[0].path, "/Data/a.txt");
+
+        // each spelling is deployed on its own, next to the restored vanilla file
+        manager.set_case_insensitive(false).unwrap();
+        manager.deploy_mods().unwrap();
+        let deployed = snapshot(&tmp.game());
+        let files: Vec<_> = deployed
+            .iter()
+            .filter_map(|(path, contents)| Some((path.as_str(), contents.as_deref()?)))
+            .collect();
+        assert_eq!(
+            files,
+            [("/DATA/a.TXT", "second"), ("/DATA/b.txt", "b"), ("/Data/a.txt", "vanilla"), ("/data/A.txt", "first")]
+        );
+        assert!(manager.conflicts().files.is_empty());
+
+        manager.purge().unwrap();
+        let purged = snapshot(&tmp.game());
+        assert_eq!(purged.keys().collect::<Vec<_>>(), ["/Data", "/Data/a.txt"]);
+        assert_eq!(purged["/Data/a.txt"].as_deref(), Some("vanilla"));
     }
 }
@@ -0,0 +1,425 @@
+use crate::node::eq_ignore_case;
+use crate::{IoResultExt, ModError};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+/// A file the manager makes up at deploy time, such as a config file merged from the fragments of several
+/// mods. It is stored under the hash of its contents, so a file with new contents is a new file, and
+/// deploying it replaces the old one like replacing any other file does.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct GeneratedFile {
+    pub(crate) name: String,
+    pub(crate) contents: Vec<u8>,
+}
+
+impl GeneratedFile {
+    pub(crate) fn new(contents: Vec<u8>) -> Self {
+        let hash = Sha256::digest(&contents);
+        let name = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+        Self { name, contents }
+    }
+
+    /// Write the file into `dir`, unless it is already there. A file that was changed in place, through a
+    /// hard link, is written again.
+    pub(crate) fn write(&self, dir: &Path) -> Result<(), ModError> {
+        let path = dir.join(&self.name);
+        if fs::read(&path).is_ok_and(|contents| contents == self.contents) {
+            return Ok(());
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, &self.contents).at(&tmp_path)?;
+        fs::rename(&tmp_path, &path).at(&path)
+    }
+}
+
+/// How a text file is encoded, so that a file made from it is written back the way the game reads it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TextEncoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    /// Anything else, such as Windows-1252, read as one char per byte so that writing it back gives the
+    /// same bytes.
+    SingleByte,
+}
+
+impl TextEncoding {
+    /// Read the text file at `path`. UTF-16 is recognized by its byte order mark.
+    pub(crate) fn read(path: &Path) -> Result<(String, Self), ModError> {
+        Ok(Self::decode(fs::read(path).at(path)?))
+    }
+
+    fn decode(bytes: Vec<u8>) -> (String, Self) {
+        let utf16 = |bytes: &[u8], unit: fn([u8; 2]) -> u16| {
+            let units = bytes.chunks(2).map(|pair| unit([pair[0], *pair.get(1).unwrap_or(&0)]));
+            char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+        };
+        match bytes.as_slice() {
+            [0xef, 0xbb, 0xbf, rest @ ..] if std::str::from_utf8(rest).is_ok() => {
+                (String::from_utf8(rest.to_vec()).unwrap(), TextEncoding::Utf8Bom)
+            }
+            [0xff, 0xfe, rest @ ..] => (utf16(rest, u16::from_le_bytes), TextEncoding::Utf16Le),
+            [0xfe, 0xff, rest @ ..] => (utf16(rest, u16::from_be_bytes), TextEncoding::Utf16Be),
+            _ => match String::from_utf8(bytes) {
+                Ok(text) => (text, TextEncoding::Utf8),
+                Err(e) => {
+                    let text = e.into_bytes().into_iter().map(char::from).collect();
+                    (text, TextEncoding::SingleByte)
+                }
+            },
+        }
+    }
+
+    /// Encode `text`. Chars a single-byte file can't hold, which can only come from mods, become `?`.
+    pub(crate) fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::Utf8Bom => [&[0xef, 0xbb, 0xbf], text.as_bytes()].concat(),
+            TextEncoding::Utf16Le => {
+                let units = text.encode_utf16().flat_map(u16::to_le_bytes);
+                [0xff, 0xfe].into_iter().chain(units).collect()
+            }
+            TextEncoding::Utf16Be => {
+                let units = text.encode_utf16().flat_map(u16::to_be_bytes);
+                [0xfe, 0xff].into_iter().chain(units).collect()
+            }
+            TextEncoding::SingleByte => text.chars().map(|c| u8::try_from(c).unwrap_or(b'?')).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConfigFormat {
+    Ini,
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// The format of the config file at `path`, from its extension.
+    pub(crate) fn of(path: &str) -> Option<Self> {
+        let (_, extension) = path.rsplit_once('.')?;
+        match extension.to_ascii_lowercase().as_str() {
+            "ini" => Some(ConfigFormat::Ini),
+            "json" => Some(ConfigFormat::Json),
+            "toml" => Some(ConfigFormat::Toml),
+            _ => None,
+        }
+    }
+
+    /// Merge `fragment` into `base`, so that the keys of the fragment win. Tables and objects are merged key
+    /// by key, everything else is replaced. Without a base, the fragment is merged into an empty file. Both
+    /// come with the path they were read from, for errors.
+    ///
+    /// INI and TOML files are edited in place, keeping the comments and layout of the base, with tables new
+    /// to it added at the end. JSON has no comments, and is written back normalized: pretty printed, with
+    /// the keys in the order they were read.
+    pub(crate) fn merge(self, base: Option<(&Path, &str)>, fragment: (&Path, &str)) -> Result<String, ModError> {
+        match self {
+            ConfigFormat::Ini => {
+                let mut merged = Ini::parse(base.map_or("", |(_, text)| text));
+                merged.merge(Ini::parse(fragment.1));
+                Ok(merged.to_text())
+            }
+            ConfigFormat::Json => {
+                let parse =
+                    |(path, text): (&Path, &str)| serde_json::from_str(text).map_err(|e| merge_failed(path, e));
+                let mut merged = match base {
+                    Some(base) => parse(base)?,
+                    None => serde_json::Value::Object(serde_json::Map::new()),
+                };
+                merge_json_value(&mut merged, parse(fragment)?);
+                // a value parsed from JSON can always be written back
+                Ok(serde_json::to_string_pretty(&merged).unwrap() + "\n")
+            }
+            ConfigFormat::Toml => {
+                let parse = |(path, text): (&Path, &str)| {
+                    text.parse::<toml_edit::DocumentMut>().map_err(|e| merge_failed(path, e))
+                };
+                let mut merged = match base {
+                    Some(base) => parse(base)?,
+                    None => toml_edit::DocumentMut::new(),
+                };
+                merge_toml_table(merged.as_table_mut(), parse(fragment)?.as_table());
+                Ok(merged.to_string())
+            }
+        }
+    }
+}
+
+fn merge_failed(path: &Path, reason: impl ToString) -> ModError {
+    ModError::MergeFailed {
+        path: path.to_path_buf(),
+        reason: reason.to_string(),
+    }
+}
+
+fn merge_json_value(base: &mut serde_json::Value, fragment: serde_json::Value) {
+    match (base, fragment) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(fragment)) => {
+            for (key, value) in fragment {
+                match base.get_mut(&key) {
+                    Some(base) => merge_json_value(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, fragment) => *base = fragment,
+    }
+}
+
+fn merge_toml_table(base: &mut dyn toml_edit::TableLike, fragment: &dyn toml_edit::TableLike) {
+    for (key, item) in fragment.iter() {
+        let mut item = item.clone();
+        place_last(&mut item);
+        match base.get_mut(key) {
+            Some(existing) => match (existing.as_table_like_mut(), item.as_table_like()) {
+                (Some(existing), Some(fragment)) => merge_toml_table(existing, fragment),
+                _ => {
+                    // replacing the item in place keeps the comments around the key and its value
+                    if let (Some(old), Some(new)) = (existing.as_value(), item.as_value_mut()) {
+                        *new.decor_mut() = old.decor().clone();
+                    }
+                    *existing = item;
+                }
+            },
+            None => {
+                base.insert(key, item);
+            }
+        }
+    }
+}
+
+/// Move the tables in `item`, which keep their position in the fragment they come from, after those of the
+/// base they are merged into.
+fn place_last(item: &mut toml_edit::Item) {
+    let place_table = |table: &mut toml_edit::Table| {
+        table.set_position(usize::MAX);
+        table.iter_mut().for_each(|(_, item)| place_last(item));
+    };
+    match item {
+        toml_edit::Item::Table(table) => place_table(table),
+        toml_edit::Item::ArrayOfTables(array) => array.iter_mut().for_each(place_table),
+        _ => {}
+    }
+}
+
+/// An INI file as lines, so that merging keeps the comments, order and formatting of the base file.
+struct Ini {
+    /// The first section holds the lines before any section header.
+    sections: Vec<IniSection>,
+    crlf: bool,
+}
+
+struct IniSection {
+    name: Option<String>,
+    lines: Vec<IniLine>,
+}
+
+enum IniLine {
+    Entry { key: String, line: String },
+    /// A section header, comment, blank or anything else that isn't a key.
+    Other(String),
+}
+
+impl Ini {
+    fn parse(text: &str) -> Self {
+        let mut sections = vec![IniSection {
+            name: None,
+            lines: Vec::new(),
+        }];
+        for line in text.lines() {
+            let trimmed = line.trim();
+            let header = trimmed.strip_prefix('[').and_then(|header| header.strip_suffix(']'));
+            if let Some(name) = header {
+                sections.push(IniSection {
+                    name: Some(name.trim().to_string()),
+                    lines: vec![IniLine::Other(line.to_string())],
+                });
+                continue;
+            }
+            let section = sections.last_mut().unwrap();
+            let comment = trimmed.starts_with(';') || trimmed.starts_with('#');
+            match trimmed.split_once('=') {
+                Some((key, _)) if !comment => section.lines.push(IniLine::Entry {
+                    key: key.trim().to_string(),
+                    line: line.to_string(),
+                }),
+                _ => section.lines.push(IniLine::Other(line.to_string())),
+            }
+        }
+        Self {
+            sections,
+            crlf: text.contains("\r\n"),
+        }
+    }
+
+    /// Set every key of `fragment` in this file. Sections and keys are matched ignoring case, as Windows
+    /// does, and new ones are added after the existing ones.
+    fn merge(&mut self, fragment: Ini) {
+        for section in fragment.sections {
+            let same_section = |existing: &IniSection| match (&existing.name, &section.name) {
+                (Some(a), Some(b)) => eq_ignore_case(a, b),
+                (a, b) => a.is_none() && b.is_none(),
+            };
+            let Some(existing) = self.sections.iter_mut().find(|existing| same_section(existing)) else {
+                self.sections.push(section);
+                continue;
+            };
+            for line in section.lines {
+                let IniLine::Entry { key, line } = line else {
+                    continue;
+                };
+                let found = existing
+                    .lines
+                    .iter_mut()
+                    .find(|existing| matches!(existing, IniLine::Entry { key: existing, .. } if eq_ignore_case(existing, &key)));
+                match found {
+                    Some(IniLine::Entry { line: existing, .. }) => *existing = line,
+                    _ => {
+                        // after the last key, so comments and blank lines at the end of the section stay there
+                        let lines = &existing.lines;
+                        let at = lines
+                            .iter()
+                            .rposition(|line| matches!(line, IniLine::Entry { .. }))
+                            .or_else(|| lines.iter().rposition(|line| !matches!(line, IniLine::Other(line) if line.trim().is_empty())))
+                            .map_or(0, |i| i + 1);
+                        existing.lines.insert(at, IniLine::Entry { key, line });
+                    }
+                }
+            }
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let newline = if self.crlf { "\r\n" } else { "\n" };
+        let mut text = String::new();
+        for section in &self.sections {
+            for line in &section.lines {
+                match line {
+                    IniLine::Entry { line, .. } | IniLine::Other(line) => text.push_str(line),
+                }
+                text.push_str(newline);
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(format: ConfigFormat, base: Option<&str>, fragment: &str) -> String {
+        let base = base.map(|base| (Path::new("base"), base));
+        format.merge(base, (Path::new("fragment"), fragment)).unwrap()
+    }
+
+    #[test]
+    fn ini_keeps_comments_and_order() {
+        let base = "; settings\n[Video]\nWidth=800\n; in pixels\nHeight=600\n\n[Audio]\nVolume=5\n";
+        let merged = merge(ConfigFormat::Ini, Some(base), "[Video]\nHeight=1080\n");
+        assert_eq!(merged, "; settings\n[Video]\nWidth=800\n; in pixels\nHeight=1080\n\n[Audio]\nVolume=5\n");
+    }
+
+    #[test]
+    fn ini_matches_sections_and_keys_ignoring_case() {
+        let merged = merge(ConfigFormat::Ini, Some("[Video]\nWidth=800\n"), "[VIDEO]\nwidth=1920\n");
+        assert_eq!(merged, "[Video]\nwidth=1920\n");
+    }
+
+    #[test]
+    fn ini_adds_new_keys_and_sections() {
+        let base = "top=1\n[Video]\nWidth=800\n; end of video\n\n[Audio]\n";
+        let fragment = "top=2\nnew=3\n[Video]\nHeight=600\n[Audio]\nVolume=5\n[Input]\nMouse=1\n";
+        let merged = merge(ConfigFormat::Ini, Some(base), fragment);
+        assert_eq!(
+            merged,
+            "top=2\nnew=3\n[Video]\nWidth=800\nHeight=600\n; end of video\n\n[Audio]\nVolume=5\n[Input]\nMouse=1\n"
+        );
+    }
+
+    #[test]
+    fn ini_keeps_crlf() {
+        let merged = merge(ConfigFormat::Ini, Some("[A]\r\nx=1\r\n"), "[A]\ny=2\n");
+        assert_eq!(merged, "[A]\r\nx=1\r\ny=2\r\n");
+    }
+
+    #[test]
+    fn ini_without_base() {
+        assert_eq!(merge(ConfigFormat::Ini, None, "[A]\nx=1\n"), "[A]\nx=1\n");
+    }
+
+    #[test]
+    fn json_merges_objects_and_replaces_the_rest() {
+        let base = r#"{"b": 1, "a": {"x": 1, "y": [1, 2]}, "c": "old"}"#;
+        let fragment = r#"{"a": {"y": [3], "z": true}, "c": {"new": 1}, "d": null}"#;
+        let merged: serde_json::Value = serde_json::from_str(&merge(ConfigFormat::Json, Some(base), fragment)).unwrap();
+        let expected = r#"{"b": 1, "a": {"x": 1, "y": [3], "z": true}, "c": {"new": 1}, "d": null}"#;
+        let expected: serde_json::Value = serde_json::from_str(expected).unwrap();
+        assert_eq!(merged, expected);
+        // keys keep the order of the base, new ones come after
+        let keys: Vec<&String> = merged.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["b", "a", "c", "d"]);
+    }
+
+    #[test]
+    fn json_reports_the_invalid_file() {
+        let result = ConfigFormat::Json.merge(Some((Path::new("base.json"), "{")), (Path::new("fragment"), "{}"));
+        assert!(matches!(result, Err(ModError::MergeFailed { path, .. }) if path == Path::new("base.json")));
+    }
+
+    #[test]
+    fn toml_merges_tables() {
+        let base = "title = \"a\"\n\n[server]\nport = 80\nhosts = [\"a\"]\n";
+        let fragment = "[server]\nhosts = [\"b\"]\ntls = true\n\n[client]\nretries = 3\n";
+        let merged: toml::Table = merge(ConfigFormat::Toml, Some(base), fragment).parse().unwrap();
+        let expected: toml::Table =
+            "title = \"a\"\n[server]\nport = 80\nhosts = [\"b\"]\ntls = true\n[client]\nretries = 3\n".parse().unwrap();
+        assert_eq!(merged, expected);
+        assert_eq!(merged.keys().collect::<Vec<_>>(), ["title", "server", "client"]);
+    }
+
+    #[test]
+    fn toml_keeps_comments_and_layout() {
+        let base = "# game settings\ntitle = \"a\"\n\n\
+            [server]\n# the port to listen on\nport   = 80 # http\n\n\
+            [log]\nlevel = \"info\"\n";
+        let fragment = "[server]\nport = 8080\n\n[client]\nretries = 3\n";
+        // new tables go after those of the base
+        let expected = "# game settings\ntitle = \"a\"\n\n\
+            [server]\n# the port to listen on\nport   = 8080 # http\n\n\
+            [log]\nlevel = \"info\"\n\n\
+            [client]\nretries = 3\n";
+        assert_eq!(merge(ConfigFormat::Toml, Some(base), fragment), expected);
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(ConfigFormat::of("/Config/Game.INI"), Some(ConfigFormat::Ini));
+        assert_eq!(ConfigFormat::of("/a.json"), Some(ConfigFormat::Json));
+        assert_eq!(ConfigFormat::of("/a.toml"), Some(ConfigFormat::Toml));
+        assert_eq!(ConfigFormat::of("/a.cfg"), None);
+    }
+
+    #[test]
+    fn encodings_round_trip() {
+        let utf16: Vec<u8> = [0xff, 0xfe].into_iter().chain("é=1".encode_utf16().flat_map(u16::to_le_bytes)).collect();
+        let samples: [(&[u8], TextEncoding, &str); 4] = [
+            (b"x=1", TextEncoding::Utf8, "x=1"),
+            (b"\xef\xbb\xbfx=1", TextEncoding::Utf8Bom, "x=1"),
+            (&utf16, TextEncoding::Utf16Le, "é=1"),
+            (b"Caf\xe9", TextEncoding::SingleByte, "Café"),
+        ];
+        for (bytes, encoding, text) in samples {
+            assert_eq!(TextEncoding::decode(bytes.to_vec()), (text.to_string(), encoding));
+            assert_eq!(encoding.encode(text), bytes);
+        }
+        assert_eq!(TextEncoding::SingleByte.encode("a€"), b"a?");
+    }
+}
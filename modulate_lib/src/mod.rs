@@ -1,9 +1,15 @@
+use crate::filter::PathFilter;
 use crate::node::Node;
-use crate::ModError;
-use semver::Version;
+use crate::{IoResultExt, ModError};
+use log::{trace, warn};
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -11,38 +17,264 @@ pub(crate) struct Mod {
     pub(crate) metadata: ModMetadata,
     pub(crate) dir: PathBuf,
     pub(crate) node: Node,
+    pub(crate) settings: ModSettings,
+    /// The config fragments and patches of the mod, which change files instead of being deployed.
+    pub(crate) fragments: Vec<Fragment>,
+    /// Why the mod couldn't be loaded, for a registered mod whose directory is gone or broken. It is kept
+    /// with nothing in it, so that it isn't forgotten, until it loads again.
+    #[serde(skip)]
+    pub(crate) load_error: Option<String>,
+}
+
+/// A config fragment or a patch, see [`ModMetadata::merge`] and [`ModMetadata::patches`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Fragment {
+    /// The path of the file the fragment changes, starting with a `/`.
+    pub(crate) path: String,
+    /// The fragment, relative to the mod directory.
+    pub(crate) file: String,
+    pub(crate) kind: FragmentKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum FragmentKind {
+    Merge,
+    Patch,
+}
+
+/// What the user set up for a mod that changes which of its files are deployed.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ModSettings {
+    /// Globs the user added to leave files of the mod out, on top of the `exclude` in its `mod.toml`.
+    pub(crate) ignore: Vec<String>,
+    /// The choices made for option groups, by group name. Groups that aren't in here use their defaults.
+    pub(crate) choices: BTreeMap<String, Vec<String>>,
+}
+
+/// Bumped whenever the layout of [`Mod`] changes, so caches written by older versions are rebuilt instead
+/// of misread.
+const CACHE_FORMAT: u32 = 7;
+
+/// Stripped from the name of a patch to get the name of the file it changes.
+const PATCH_EXTENSION: &str = ".patch";
+
+/// What a cached scan was made from, written at the start of the cache file. The cache is only used while
+/// all of it still matches the mod directory.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CacheKey {
+    format: u32,
+    /// Hash of `mod.toml`.
+    metadata_hash: [u8; 32],
+    /// Hash of the path, size and modification time of everything else in the mod.
+    fingerprint: [u8; 32],
+    /// Hash of the user's settings for the mod.
+    settings_hash: [u8; 32],
+}
+
+impl CacheKey {
+    fn for_dir(dir: &Path, settings: &ModSettings) -> Result<Self, ModError> {
+        let metadata_path = dir.join("mod.toml");
+        let metadata = fs::read(&metadata_path).at(&metadata_path)?;
+        let mut fingerprint = Sha256::new();
+        fingerprint_dir(dir, Path::new(""), &mut fingerprint)?;
+        // serializing into memory can't fail
+        let settings = bincode::serialize(settings).unwrap();
+        Ok(Self {
+            format: CACHE_FORMAT,
+            metadata_hash: Sha256::digest(metadata).into(),
+            fingerprint: fingerprint.finalize().into(),
+            settings_hash: Sha256::digest(settings).into(),
+        })
+    }
+}
+
+/// Feed every entry below `dir` into `hasher`, in a stable order. `relative` is the path of `dir` within
+/// the mod.
+fn fingerprint_dir(dir: &Path, relative: &Path, hasher: &mut Sha256) -> Result<(), ModError> {
+    let mut entries = fs::read_dir(dir)
+        .at(dir)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()
+        .at(dir)?;
+    entries.sort();
+    for name in entries {
+        if name == "mod.toml" || name == "mod.bin" {
+            continue;
+        }
+        let path = dir.join(&name);
+        let relative = relative.join(&name);
+        let metadata = fs::metadata(&path).at(&path)?;
+        hasher.update(relative.as_os_str().as_encoded_bytes());
+        hasher.update([0, metadata.is_dir() as u8]);
+        if metadata.is_dir() {
+            fingerprint_dir(&path, &relative, hasher)?;
+        } else {
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            hasher.update(metadata.len().to_le_bytes());
+            hasher.update(modified.as_secs().to_le_bytes());
+            hasher.update(modified.subsec_nanos().to_le_bytes());
+        }
+    }
+    Ok(())
 }
 
 impl Mod {
-    pub(crate) fn new(dir: PathBuf) -> Result<Self, ModError> {
+    /// Load the mod in `dir`, from its cache in `cache_dir` if that still matches the directory, or by
+    /// scanning the directory and rewriting the cache otherwise. Nothing is ever written to `dir`. Which
+    /// files are deployed depends on the `mod.toml` and the user's `settings`.
+    pub(crate) fn new(dir: PathBuf, cache_dir: &Path, settings: ModSettings) -> Result<Self, ModError> {
         if !Path::new(&dir).is_dir() {
             return Err(ModError::DirNotFound(dir.to_string_lossy().to_string()));
         }
-        let dir = fs::canonicalize(dir).unwrap();
-        // check if serialized mod exists
-        let bin_path = dir.join("mod.bin");
-        if bin_path.exists() {
-            let file = fs::File::open(bin_path).unwrap();
-            return Ok(bincode::deserialize_from(file).unwrap());
+        let dir = fs::canonicalize(&dir).at(&dir)?;
+        let metadata = ModMetadata::from_dir(&dir)?;
+        let cache_path = Self::cache_path(cache_dir, metadata.uuid, &dir);
+        let key = CacheKey::for_dir(&dir, &settings)?;
+        match Self::load_cache(&cache_path, &key) {
+            Ok(Some(r#mod)) if r#mod.dir == dir => return Ok(r#mod),
+            Ok(Some(_)) => trace!("Mod cache {} was made for another directory", cache_path.display()),
+            Ok(None) => trace!("Mod cache {} is missing or stale", cache_path.display()),
+            Err(e) => warn!("Rebuilding unreadable mod cache: {}", e),
         }
 
-        // read metadata
-        let metadata_path = dir.join("mod.toml");
-        if !metadata_path.exists() {
-            return Err(ModError::ModMetadataMissing(dir.to_string_lossy().to_string()));
+        let filter = PathFilter::new(&metadata, &settings.ignore)?;
+        let moved = !metadata.root().is_empty() || !metadata.target().is_empty();
+        let mut node = Node::from_subdir(&dir, metadata.root(), moved, &filter)?;
+        // chosen options are laid over the files outside of them, and over the options before them, and
+        // mappings over all of those
+        for choice in metadata.chosen(&settings.choices) {
+            node.merge(Node::from_subdir(&dir, choice.dir(), true, &filter)?);
+        }
+        for mapping in &metadata.mappings {
+            if let Some(mapped) = Node::from_entry(&dir, mapping.from(), &filter)? {
+                node.merge(mapped.nest(mapping.to()));
+            }
+        }
+        let mut node = node.nest(metadata.target());
+        let mut merged = Vec::new();
+        node.take_files("", &|file| filter.merged(file), &mut merged);
+        let mut patches = Vec::new();
+        node.take_files("", &|file| filter.patched(file), &mut patches);
+        let merged = merged.into_iter().map(|(path, file)| Fragment {
+            path,
+            file,
+            kind: FragmentKind::Merge,
+        });
+        let patches = patches.into_iter().map(|(path, file)| Fragment {
+            // a patch mapped to another name patches the file with that name
+            path: path.strip_suffix(PATCH_EXTENSION).unwrap_or(&path).to_string(),
+            file,
+            kind: FragmentKind::Patch,
+        });
+        let r = Self {
+            metadata,
+            node,
+            dir,
+            settings,
+            fragments: merged.chain(patches).collect(),
+            load_error: None,
+        };
+        // the cache only saves time, so a mod whose cache can't be written still loads
+        if let Err(e) = r.save_cache(&cache_path, &key) {
+            warn!("{}", e);
         }
-        let metadata =
-            toml::from_str::<ModMetadata>(&fs::read_to_string(metadata_path).unwrap()).unwrap();
-        let r =
+        Ok(r)
+    }
+
+    /// A mod the manager makes up itself, without a `mod.toml`. The manager puts its files into `dir` and
+    /// sets its tree.
+    pub(crate) fn built_in(name: &str, uuid: Uuid, dir: PathBuf) -> Self {
+        let metadata = ModMetadata {
+            name: name.to_string(),
+            version: Version::new(0, 0, 0),
+            uuid,
+            requires: Vec::new(),
+            conflicts_with: Vec::new(),
+            optional: Vec::new(),
+            load_after: Vec::new(),
+            load_before: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            options: Vec::new(),
+            root: String::new(),
+            target: String::new(),
+            mappings: Vec::new(),
+            merge: Vec::new(),
+            patches: Vec::new(),
+        };
         Self {
             metadata,
-            node: Node::from_path(&dir).unwrap(),
+            node: Node::Dir {
+                name: "root".to_string(),
+                children: HashMap::new(),
+            },
             dir,
+            settings: ModSettings::default(),
+            fragments: Vec::new(),
+            load_error: None,
+        }
+    }
+
+    /// A registered mod that failed to load with `error`, standing in for it under the name of its directory.
+    pub(crate) fn unloadable(uuid: Uuid, dir: PathBuf, settings: ModSettings, error: &ModError) -> Self {
+        let name = dir.file_name().unwrap_or_default().to_string_lossy().to_string();
+        Self {
+            settings,
+            load_error: Some(error.to_string()),
+            ..Self::built_in(&name, uuid, dir)
+        }
+    }
+
+    /// The file in the mod directory that is deployed at `path`, a path relative to the working directory.
+    pub(crate) fn file_path(&self, path: &str) -> PathBuf {
+        let path = path.trim_start_matches('/');
+        match self.node.locate(path) {
+            Some(located) => self.dir.join(located),
+            None => self.dir.join(path),
+        }
+    }
+
+    /// Where the scan of the mod with `uuid` in `dir` is cached. The path is part of the name, so two
+    /// copies of the same mod don't overwrite each other's cache.
+    pub(crate) fn cache_path(cache_dir: &Path, uuid: Uuid, dir: &Path) -> PathBuf {
+        let dir_hash = Sha256::digest(dir.as_os_str().as_encoded_bytes());
+        let dir_hash: String = dir_hash[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+        cache_dir.join(format!("{}-{}.bin", uuid, dir_hash))
+    }
+
+    /// Read a cached mod, or `None` if there is no cache or it was made from a different `key`.
+    fn load_cache(path: &Path, key: &CacheKey) -> Result<Option<Self>, ModError> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).at(path),
+        };
+        let len = file.metadata().at(path)?.len();
+        let mut reader = io::BufReader::new(file);
+        let cache_error = |source| ModError::ModCacheFailed {
+            path: path.to_path_buf(),
+            source,
         };
-        let file = fs::File::create(bin_path).unwrap();
-        bincode::serialize_into(file, &r).unwrap();
+        let cached: CacheKey = crate::deserialize_limited(&mut reader, len).map_err(cache_error)?;
+        if cached != *key {
+            return Ok(None);
+        }
+        crate::deserialize_limited(&mut reader, len).map(Some).map_err(cache_error)
+    }
 
-        Ok(r)
+    fn save_cache(&self, path: &Path, key: &CacheKey) -> Result<(), ModError> {
+        let cache_error = |source| ModError::ModCacheFailed {
+            path: path.to_path_buf(),
+            source,
+        };
+        let mut writer = io::BufWriter::new(fs::File::create(path).at(path)?);
+        bincode::serialize_into(&mut writer, key).map_err(cache_error)?;
+        bincode::serialize_into(&mut writer, self).map_err(cache_error)?;
+        writer.flush().at(path)
     }
 }
 
@@ -51,4 +283,350 @@ pub struct ModMetadata {
     pub name: String,
     pub version: Version,
     pub uuid: Uuid,
+    /// Mods that must be active for this one to be deployed.
+    #[serde(default)]
+    pub requires: Vec<ModRelation>,
+    /// Mods that can't be active at the same time as this one.
+    #[serde(default)]
+    pub conflicts_with: Vec<ModRelation>,
+    /// Mods this one works with but doesn't need. If one is active, its version should match.
+    #[serde(default)]
+    pub optional: Vec<ModRelation>,
+    /// Mods this one is loaded after, so that its files win over theirs.
+    #[serde(default)]
+    pub load_after: Vec<Uuid>,
+    /// Mods this one is loaded before, so that their files win over its.
+    #[serde(default)]
+    pub load_before: Vec<Uuid>,
+    /// Globs of the files to deploy. If there are any, everything else in the mod is left out.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Globs of files not to deploy, such as readmes or screenshots. A glob without a `/` matches names
+    /// anywhere in the mod.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Groups of optional components the user chooses from, each in a subdirectory of the mod.
+    #[serde(default)]
+    pub options: Vec<OptionGroup>,
+    /// The subdirectory of the mod with the files to deploy, for mods packaged with extra folders around
+    /// them. Nothing outside of it is deployed, except for options and mappings.
+    #[serde(default)]
+    pub root: String,
+    /// The subdirectory of the working directory the mod is deployed into.
+    #[serde(default)]
+    pub target: String,
+    /// Files or dirs of the mod deployed somewhere else than where they are.
+    #[serde(default)]
+    pub mappings: Vec<PathMapping>,
+    /// Globs of config fragments: INI, JSON or TOML files with only the sections and keys the mod changes.
+    /// Instead of replacing the file at their path, they are merged into it at deploy time, in load order,
+    /// so the keys of the mods loaded later win.
+    #[serde(default)]
+    pub merge: Vec<String>,
+    /// Globs of patches: unified diffs, as written by `diff -u`, named after the file they change with
+    /// `.patch` added, such as `settings.cfg.patch`. Instead of being deployed, they are applied to the file
+    /// at deploy time, in load order. Hunks that don't apply are reported and left out.
+    #[serde(default)]
+    pub patches: Vec<String>,
+}
+
+/// A file or dir of a mod deployed at another path, declared in `mod.toml`:
+///
+/// ```toml
+/// [[mappings]]
+/// from = "optional/settings.ini"
+/// to = "config/settings.ini"
+/// ```
+///
+/// `from` is relative to the mod directory, `to` to the [`target`](ModMetadata::target) of the mod. What
+/// is mapped replaces the files the mod has at `to`, and is not deployed at `from`. Only a dir can be mapped
+/// to `""`, to deploy what is in it at the target.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PathMapping {
+    pub from: String,
+    pub to: String,
+}
+
+impl PathMapping {
+    pub(crate) fn from(&self) -> &str {
+        self.from.trim_matches('/')
+    }
+
+    pub(crate) fn to(&self) -> &str {
+        self.to.trim_matches('/')
+    }
+}
+
+/// A named group of choices, declared in `mod.toml`:
+///
+/// ```toml
+/// [[options]]
+/// name = "Textures"
+/// kind = "single"
+/// choices = [
+///     { name = "2K", dir = "textures-2k", default = true },
+///     { name = "4K", dir = "textures-4k" },
+/// ]
+/// ```
+///
+/// The files of a chosen option are deployed as if its directory was the root of the mod, replacing
+/// those outside of any option. The directories of options that aren't chosen are never deployed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct OptionGroup {
+    pub name: String,
+    #[serde(default)]
+    pub kind: OptionKind,
+    pub choices: Vec<OptionChoice>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OptionKind {
+    /// Exactly one choice is made. If none is marked as the default, the first one is.
+    #[default]
+    Single,
+    /// Any number of choices are made, by default those marked as such.
+    Multi,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct OptionChoice {
+    pub name: String,
+    /// The subdirectory of the mod with the files of this choice.
+    pub dir: String,
+    #[serde(default)]
+    pub default: bool,
+}
+
+impl OptionChoice {
+    pub(crate) fn dir(&self) -> &str {
+        self.dir.trim_matches('/')
+    }
+}
+
+impl OptionGroup {
+    /// The choices made for this group, either `chosen` by the user or the defaults.
+    pub fn chosen<'a>(&'a self, chosen: Option<&[String]>) -> Vec<&'a OptionChoice> {
+        let defaults = || -> Vec<&OptionChoice> {
+            match self.kind {
+                OptionKind::Single => self
+                    .choices
+                    .iter()
+                    .find(|choice| choice.default)
+                    .or(self.choices.first())
+                    .into_iter()
+                    .collect(),
+                OptionKind::Multi => self.choices.iter().filter(|choice| choice.default).collect(),
+            }
+        };
+        let Some(chosen) = chosen else {
+            return defaults();
+        };
+        let choices: Vec<&OptionChoice> =
+            self.choices.iter().filter(|choice| chosen.contains(&choice.name)).collect();
+        // a choice that was made for an older version of the mod may not exist anymore
+        if self.kind == OptionKind::Single && choices.len() != 1 {
+            return defaults();
+        }
+        choices
+    }
+
+    /// Check that `chosen` are names of choices of this group, and that a single-choice group gets one.
+    pub(crate) fn validate(&self, chosen: &[String]) -> Result<(), ModError> {
+        if let Some(unknown) = chosen.iter().find(|name| !self.choices.iter().any(|choice| &choice.name == *name)) {
+            return Err(ModError::InvalidChoice(format!("{:?} has no choice {:?}", self.name, unknown)));
+        }
+        if self.kind == OptionKind::Single && chosen.len() != 1 {
+            return Err(ModError::InvalidChoice(format!("{:?} takes exactly one choice", self.name)));
+        }
+        Ok(())
+    }
+}
+
+impl ModMetadata {
+    /// Read the `mod.toml` at the root of a mod directory.
+    pub(crate) fn from_dir(dir: &Path) -> Result<Self, ModError> {
+        let metadata_path = dir.join("mod.toml");
+        if !metadata_path.exists() {
+            return Err(ModError::ModMetadataMissing(dir.to_string_lossy().to_string()));
+        }
+        let metadata = toml::from_str::<ModMetadata>(&fs::read_to_string(&metadata_path).at(&metadata_path)?)
+            .map_err(|e| ModError::InvalidModMetadata(format!("{}: {}", metadata_path.display(), e)))?;
+        metadata
+            .check_options()
+            .and_then(|()| metadata.check_paths(dir))
+            .map_err(|e| ModError::InvalidModMetadata(format!("{}: {}", metadata_path.display(), e)))?;
+        Ok(metadata)
+    }
+
+    fn check_options(&self) -> Result<(), String> {
+        for (i, group) in self.options.iter().enumerate() {
+            if self.options[..i].iter().any(|other| other.name == group.name) {
+                return Err(format!("option group {:?} is declared twice", group.name));
+            }
+            if group.kind == OptionKind::Single && group.choices.iter().filter(|choice| choice.default).count() > 1 {
+                return Err(format!("option group {:?} has more than one default", group.name));
+            }
+            for (j, choice) in group.choices.iter().enumerate() {
+                if group.choices[..j].iter().any(|other| other.name == choice.name) {
+                    return Err(format!("option group {:?} has two choices named {:?}", group.name, choice.name));
+                }
+                if choice.dir().is_empty() || !is_relative(choice.dir()) {
+                    return Err(format!("option {:?} has an invalid dir {:?}", choice.name, choice.dir));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_paths(&self, dir: &Path) -> Result<(), String> {
+        if !is_relative(self.root()) {
+            return Err(format!("invalid root {:?}", self.root));
+        }
+        if !is_relative(self.target()) {
+            return Err(format!("invalid target {:?}", self.target));
+        }
+        for mapping in &self.mappings {
+            if mapping.from().is_empty() || !is_relative(mapping.from()) || !is_relative(mapping.to()) {
+                return Err(format!("invalid mapping from {:?} to {:?}", mapping.from, mapping.to));
+            }
+            // a dir mapped to `""` is deployed at the target, but a file would replace it
+            if mapping.to().is_empty() && !dir.join(mapping.from()).is_dir() {
+                return Err(format!("mapping from {:?} needs a path to map the file to", mapping.from));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn root(&self) -> &str {
+        self.root.trim_matches('/')
+    }
+
+    pub(crate) fn target(&self) -> &str {
+        self.target.trim_matches('/')
+    }
+
+    /// The option group called `name`.
+    pub fn option_group(&self, name: &str) -> Option<&OptionGroup> {
+        self.options.iter().find(|group| group.name == name)
+    }
+
+    /// Every choice made for the option groups, in the order they are declared, given the user's `choices`.
+    pub(crate) fn chosen<'a>(&'a self, choices: &BTreeMap<String, Vec<String>>) -> Vec<&'a OptionChoice> {
+        self.options
+            .iter()
+            .flat_map(|group| group.chosen(choices.get(&group.name).map(Vec::as_slice)))
+            .collect()
+    }
+}
+
+/// A reference from one mod to another, as declared in `mod.toml`:
+///
+/// ```toml
+/// [[requires]]
+/// uuid = "6d1f6a9e-8f4a-4a53-9a5e-8f5bc1f0c3a2"
+/// version = ">=1.2, <2"
+/// ```
+///
+/// Leaving out `version` matches any version.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ModRelation {
+    pub uuid: Uuid,
+    #[serde(default = "any_version")]
+    pub version: VersionReq,
+}
+
+fn any_version() -> VersionReq {
+    VersionReq::STAR
+}
+
+/// Whether `path` stays within the dir it is relative to.
+fn is_relative(path: &str) -> bool {
+    Path::new(path).components().all(|part| matches!(part, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const METADATA: &str = "name = \"mod\"\nversion = \"1.0.0\"\nuuid = \"00000000-0000-0000-0000-000000000001\"\n";
+
+    /// A scratch dir with a mod with `files` in `mod` and an empty cache dir in `cache`.
+    fn mod_dir(name: &str, files: &[(&str, &str)]) -> TempDir {
+        let tmp = TempDir::new(&format!("mod-{}", name));
+        let root = tmp.path();
+        fs::create_dir(root.join("cache")).unwrap();
+        fs::create_dir(root.join("mod")).unwrap();
+        fs::write(root.join("mod/mod.toml"), METADATA).unwrap();
+        for (path, contents) in files {
+            fs::write(root.join("mod").join(path), contents).unwrap();
+        }
+        tmp
+    }
+
+    fn load(root: &Path, settings: &ModSettings) -> Mod {
+        Mod::new(root.join("mod"), &root.join("cache"), settings.clone()).unwrap()
+    }
+
+    #[test]
+    fn cache_key_changes_with_the_mod_and_settings() {
+        let tmp = mod_dir("key", &[("a.txt", "a")]);
+        let root = tmp.path();
+        let dir = root.join("mod");
+        let settings = ModSettings::default();
+        let key = CacheKey::for_dir(&dir, &settings).unwrap();
+        assert_eq!(CacheKey::for_dir(&dir, &settings).unwrap(), key);
+        // caches older versions wrote into the mod are not part of it
+        fs::write(dir.join("mod.bin"), "old cache").unwrap();
+        assert_eq!(CacheKey::for_dir(&dir, &settings).unwrap(), key);
+
+        fs::write(dir.join("a.txt"), "changed").unwrap();
+        let changed = CacheKey::for_dir(&dir, &settings).unwrap();
+        assert_ne!(changed, key);
+        fs::write(dir.join("mod.toml"), format!("{}exclude = [\"*.md\"]\n", METADATA)).unwrap();
+        let key = CacheKey::for_dir(&dir, &settings).unwrap();
+        assert_ne!(key, changed);
+        let settings = ModSettings {
+            ignore: vec!["a.txt".to_string()],
+            ..ModSettings::default()
+        };
+        assert_ne!(CacheKey::for_dir(&dir, &settings).unwrap(), key);
+    }
+
+    #[test]
+    fn changed_mod_is_scanned_again() {
+        let tmp = mod_dir("rescan", &[("a.txt", "a")]);
+        let root = tmp.path();
+        let mut settings = ModSettings::default();
+        let r#mod = load(root, &settings);
+        let cache_path = Mod::cache_path(&root.join("cache"), r#mod.metadata.uuid, &r#mod.dir);
+        assert!(cache_path.exists());
+        assert!(!root.join("mod/mod.bin").exists());
+        assert!(r#mod.node.locate("b.txt").is_none());
+
+        fs::write(root.join("mod/b.txt"), "b").unwrap();
+        assert!(load(root, &settings).node.locate("b.txt").is_some());
+        fs::write(root.join("mod/mod.toml"), format!("{}exclude = [\"b.txt\"]\n", METADATA)).unwrap();
+        assert!(load(root, &settings).node.locate("b.txt").is_none());
+        settings.ignore.push("a.txt".to_string());
+        assert!(load(root, &settings).node.locate("a.txt").is_none());
+    }
+
+    #[test]
+    fn corrupt_cache_is_rebuilt() {
+        let tmp = mod_dir("corrupt", &[("a.txt", "a")]);
+        let root = tmp.path();
+        let settings = ModSettings::default();
+        let r#mod = load(root, &settings);
+        let cache_path = Mod::cache_path(&root.join("cache"), r#mod.metadata.uuid, &r#mod.dir);
+        let key = CacheKey::for_dir(&r#mod.dir, &settings).unwrap();
+        let cache = fs::read(&cache_path).unwrap();
+        for corrupt in [&b"garbage"[..], &cache[..cache.len() / 2]] {
+            fs::write(&cache_path, corrupt).unwrap();
+            assert!(Mod::load_cache(&cache_path, &key).is_err());
+            assert!(load(root, &settings).node.locate("a.txt").is_some());
+            assert!(Mod::load_cache(&cache_path, &key).unwrap().is_some());
+        }
+    }
 }
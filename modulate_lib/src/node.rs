@@ -1,8 +1,11 @@
-use crate::ModKey;
+use crate::filter::PathFilter;
+use crate::{IoResultExt, ModError};
 use std::collections::HashMap;
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) enum Node {
@@ -12,35 +15,193 @@ pub(crate) enum Node {
     },
     File {
         name: String,
+        /// Where the file is in the mod directory, if that isn't where it is deployed to.
+        origin: Option<String>,
     },
 }
 
 impl Node {
-    pub(crate) fn from_path(path: &Path) -> Option<Self> {
-        let name = path.file_name().unwrap().to_str().unwrap();
-        if name == "mod.toml" {
-            return None;
-        }
-        Some(if path.is_dir() {
-            let children = fs::read_dir(path)
-                .unwrap()
-                .filter_map(|entry| {
-                    let entry = entry.unwrap();
-                    let node = Node::from_path(&entry.path());
-                    node.map(|node| (node.name().to_string(), node))
-                })
-                .collect();
-            Self::Dir {
+    /// Scan the subdirectory `relative` of the mod directory `root` as if it was the root of the mod, leaving
+    /// out whatever `filter` doesn't deploy. If `moved` is set, the files remember where they are in their
+    /// `origin`.
+    pub(crate) fn from_subdir(root: &Path, relative: &str, moved: bool, filter: &PathFilter) -> Result<Self, ModError> {
+        let path = root.join(relative);
+        // an excluded dir deploys nothing, even if it is the root of the mod or of an option
+        let children = if filter.excluded_with_parents(relative) {
+            HashMap::new()
+        } else {
+            let included = filter.included_with_parents(relative);
+            Self::scan_children(&path, relative, included, moved, filter)?.0
+        };
+        Ok(Self::Dir {
+            name: root.file_name().unwrap_or_default().to_string_lossy().to_string(),
+            children,
+        })
+    }
+
+    /// Scan the file or dir at `relative` in the mod directory `root`, if `filter` deploys it. Its files
+    /// remember where they are.
+    pub(crate) fn from_entry(root: &Path, relative: &str, filter: &PathFilter) -> Result<Option<Self>, ModError> {
+        let path = root.join(relative);
+        fs::symlink_metadata(&path).at(&path)?;
+        if filter.excluded_with_parents(relative) {
+            return Ok(None);
+        }
+        let parent = relative.rsplit_once('/').map_or("", |(parent, _)| parent);
+        Self::scan(&path, relative, filter.included_with_parents(parent), true, filter)
+    }
+
+    /// Scan `path`, which is at `relative` in the mod directory. `included` is set if a dir it is in was
+    /// included, `moved` if it isn't deployed at `relative`.
+    fn scan(
+        path: &Path,
+        relative: &str,
+        included: bool,
+        moved: bool,
+        filter: &PathFilter,
+    ) -> Result<Option<Self>, ModError> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| ModError::InvalidFileName(path.to_path_buf()))?;
+        // `mod.bin` is a scan cache that older versions wrote into the mod directory
+        if name == "mod.toml" || name == "mod.bin" || filter.excluded(relative) {
+            return Ok(None);
+        }
+        let included = included || filter.included(relative);
+        Ok(if path.is_dir() {
+            let (children, empty) = Self::scan_children(path, relative, included, moved, filter)?;
+            // a dir is only created for the files in it, unless it is empty in the mod as well
+            (!children.is_empty() || (empty && included)).then(|| Self::Dir {
                 name: name.to_string(),
                 children,
-            }
+            })
         } else {
-            Self::File {
+            included.then(|| Self::File {
                 name: name.to_string(),
-            }
+                origin: moved.then(|| relative.to_string()),
+            })
         })
     }
 
+    /// Scan the entries of the dir at `path`. Also returns whether the dir is empty, before filtering.
+    fn scan_children(
+        path: &Path,
+        relative: &str,
+        included: bool,
+        moved: bool,
+        filter: &PathFilter,
+    ) -> Result<(HashMap<String, Node>, bool), ModError> {
+        let mut children = HashMap::new();
+        let mut empty = true;
+        for entry in fs::read_dir(path).at(path)? {
+            let entry = entry.at(path)?;
+            empty = false;
+            let child_name = entry.file_name().to_string_lossy().to_string();
+            let child_relative = match relative {
+                "" => child_name,
+                _ => format!("{}/{}", relative, child_name),
+            };
+            if filter.remapped(&child_relative) {
+                continue;
+            }
+            if let Some(node) = Node::scan(&entry.path(), &child_relative, included, moved, filter)? {
+                children.insert(node.name().to_string(), node);
+            }
+        }
+        Ok((children, empty))
+    }
+
+    /// Lay `other` over this node, its files replacing those at the same paths.
+    pub(crate) fn merge(&mut self, other: Node) {
+        match (self, other) {
+            (Node::Dir { children, .. }, Node::Dir { children: other_children, .. }) => {
+                for (name, other) in other_children {
+                    match children.get_mut(&name) {
+                        Some(node) => node.merge(other),
+                        None => {
+                            children.insert(name, other);
+                        }
+                    }
+                }
+            }
+            (node, other) => *node = other,
+        }
+    }
+
+    /// Take the files whose path in the mod directory `take` accepts out of this tree, collecting their
+    /// deployed paths, starting with a `/`, along with their paths in the mod directory.
+    pub(crate) fn take_files(&mut self, current_path: &str, take: &dyn Fn(&str) -> bool, taken: &mut Vec<(String, String)>) {
+        let Node::Dir { children, .. } = self else {
+            return;
+        };
+        children.retain(|name, node| {
+            let path = format!("{}/{}", current_path, name);
+            match node {
+                Node::Dir { .. } => {
+                    node.take_files(&path, take, taken);
+                    true
+                }
+                Node::File { origin, .. } => {
+                    let file = origin.clone().unwrap_or_else(|| path[1..].to_string());
+                    if !take(&file) {
+                        return true;
+                    }
+                    taken.push((path, file));
+                    false
+                }
+            }
+        });
+    }
+
+    /// Collect where every file of this tree that isn't deployed where it is comes from.
+    pub(crate) fn origins<'a>(&'a self, origins: &mut Vec<&'a str>) {
+        match self {
+            Node::Dir { children, .. } => children.values().for_each(|node| node.origins(origins)),
+            Node::File { origin, .. } => origins.extend(origin.as_deref()),
+        }
+    }
+
+    /// Put this node at `path`, relative to the root and without a leading `/`, in an otherwise empty tree.
+    pub(crate) fn nest(mut self, path: &str) -> Node {
+        let mut names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+        let Some(last) = names.pop() else {
+            return self;
+        };
+        match &mut self {
+            Node::Dir { name, .. } | Node::File { name, .. } => *name = last.to_string(),
+        }
+        for name in names.into_iter().rev().chain([""]) {
+            self = Node::Dir {
+                name: name.to_string(),
+                children: HashMap::from([(self.name().to_string(), self)]),
+            };
+        }
+        self
+    }
+
+    /// Where the file deployed at `path`, relative to the root and without a leading `/`, is in the mod
+    /// directory. Names are matched exactly if possible, and ignoring case otherwise, since a case-insensitive
+    /// merge may deploy the file with another spelling. `None` if the mod doesn't have the file.
+    pub(crate) fn locate(&self, path: &str) -> Option<String> {
+        let mut node = self;
+        let mut located = Vec::new();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let Node::Dir { children, .. } = node else {
+                return None;
+            };
+            node = children
+                .get(name)
+                .or_else(|| children.iter().find(|(child, _)| eq_ignore_case(child, name)).map(|(_, node)| node))?;
+            located.push(node.name());
+        }
+        match node {
+            Node::File { origin: Some(origin), .. } => Some(origin.clone()),
+            Node::File { origin: None, .. } => Some(located.join("/")),
+            Node::Dir { .. } => None,
+        }
+    }
+
     pub(crate) fn name(&self) -> &str {
         match self {
             Node::Dir { name, .. } => name,
@@ -49,7 +210,7 @@ impl Node {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) enum SourcedNode {
     Dir {
         name: String,
@@ -57,12 +218,14 @@ pub(crate) enum SourcedNode {
     },
     File {
         name: String,
-        source: ModKey,
+        source: Uuid,
+        /// Where the file is in the source mod, if that isn't where it is deployed to.
+        origin: Option<String>,
     },
 }
 
 impl SourcedNode {
-    pub(crate) fn from_node(node: &Node, source: ModKey) -> Self {
+    pub(crate) fn from_node(node: &Node, source: Uuid) -> Self {
         match node {
             Node::Dir { name, children } => {
                 let children = children
@@ -74,14 +237,27 @@ impl SourcedNode {
                     children,
                 }
             }
-            Node::File { name } => Self::File {
+            Node::File { name, origin } => Self::File {
                 name: name.clone(),
                 source,
+                origin: origin.clone(),
             },
         }
     }
 
-    pub(crate) fn overwrite_with(&mut self, node: &Node, source: ModKey) {
+    /// Lay `node` from mod `source` over this tree. Every file that replaces another one is recorded in
+    /// `overwrites`, in the order it happens.
+    ///
+    /// If `case_insensitive` is set, names that only differ in case are the same path, and the tree keeps
+    /// the spelling it had. A file replaced by one spelled differently keeps its name as well.
+    pub(crate) fn overwrite_with(
+        &mut self,
+        node: &Node,
+        source: Uuid,
+        current_path: &str,
+        overwrites: &mut Vec<Overwrite>,
+        case_insensitive: bool,
+    ) {
         match (&mut *self, node) {
             (
                 SourcedNode::Dir {
@@ -92,24 +268,159 @@ impl SourcedNode {
                     ..
                 },
             ) => {
+                let mut new_children: Vec<_> = new_children.iter().collect();
+                // a mod can have several spellings of a name, sorting makes the one that is kept the same every time
+                if case_insensitive {
+                    new_children.sort_by_key(|(name, _)| *name);
+                }
                 for (new_name, new_node) in new_children {
                     let mut found = false;
                     for (name, node) in &mut *children {
-                        if name == new_name {
+                        if name == new_name || (case_insensitive && eq_ignore_case(name, new_name)) {
                             found = true;
-                            node.overwrite_with(new_node, source);
+                            let path = format!("{}/{}", current_path, name);
+                            node.overwrite_with(new_node, source, &path, overwrites, case_insensitive);
                             break;
                         }
                     }
                     if !found {
-                        children.insert(new_name.clone(), SourcedNode::from_node(new_node, source));
+                        let node = match new_node {
+                            // merged like any other dir, for the spellings in it
+                            Node::Dir { .. } if case_insensitive => {
+                                let mut node = SourcedNode::Dir {
+                                    name: new_name.clone(),
+                                    children: HashMap::new(),
+                                };
+                                let path = format!("{}/{}", current_path, new_name);
+                                node.overwrite_with(new_node, source, &path, overwrites, case_insensitive);
+                                node
+                            }
+                            _ => SourcedNode::from_node(new_node, source),
+                        };
+                        children.insert(new_name.clone(), node);
                     }
                 }
             }
-            (SourcedNode::File { .. }, Node::File { .. }) => {
+            (SourcedNode::File { name, source: replaced, .. }, Node::File { .. }) => {
+                // only a case-insensitive merge can find two spellings of a file in the same mod
+                if *replaced != source {
+                    overwrites.push(Overwrite {
+                        path: current_path.to_string(),
+                        replaced: *replaced,
+                        by: source,
+                        type_mismatch: false,
+                    });
+                }
+                let name = std::mem::take(name);
                 *self = SourcedNode::from_node(node, source);
+                self.rename(name);
+            }
+            _ => {
+                // a file where the tree has a dir or the other way round: the mod loaded later wins, like it
+                // does for files, and replaces everything the earlier mods have at this path
+                let name = self.name().to_string();
+                let mut replaced = Vec::new();
+                self.sources(&mut replaced);
+                for replaced in replaced {
+                    overwrites.push(Overwrite {
+                        path: current_path.to_string(),
+                        replaced,
+                        by: source,
+                        type_mismatch: true,
+                    });
+                }
+                *self = SourcedNode::from_node(node, source);
+                self.rename(name);
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            SourcedNode::Dir { name, .. } | SourcedNode::File { name, .. } => name,
+        }
+    }
+
+    fn rename(&mut self, new_name: String) {
+        match self {
+            SourcedNode::Dir { name, .. } | SourcedNode::File { name, .. } => *name = new_name,
+        }
+    }
+
+    /// Spell the entries of this tree like the dirs at `paths` do, where they have them in another case,
+    /// preferring the spellings of the first dirs. Used by case-insensitive merges with the working
+    /// directory and the backup dir, so that mods are deployed into the existing entries, and replace
+    /// backed up vanilla files instead of ending up next to them once those are restored.
+    pub(crate) fn match_case(&mut self, paths: &[PathBuf]) {
+        let SourcedNode::Dir { children, .. } = self else {
+            return;
+        };
+        // a dir that can't be read is treated as missing, deploying will report the actual error
+        let existing: Vec<String> = paths
+            .iter()
+            .filter_map(|path| fs::read_dir(path).ok())
+            .flat_map(|entries| entries.filter_map(|entry| entry.ok()?.file_name().into_string().ok()))
+            .collect();
+        let renames: Vec<(String, String)> = children
+            .keys()
+            .filter(|&name| !existing.contains(name))
+            .filter_map(|name| {
+                let spelling = existing.iter().find(|existing| eq_ignore_case(existing, name))?;
+                Some((name.clone(), spelling.clone()))
+            })
+            .collect();
+        for (name, spelling) in renames {
+            if let Some(mut node) = children.remove(&name) {
+                node.rename(spelling.clone());
+                children.insert(spelling, node);
+            }
+        }
+        for (name, node) in children {
+            let paths: Vec<PathBuf> = paths.iter().map(|path| path.join(&*name)).collect();
+            node.match_case(&paths);
+        }
+    }
+
+    /// `path` spelled like the entries of this tree, ignoring case. The part of it the tree doesn't have
+    /// is kept as it is.
+    pub(crate) fn spelling_of(&self, path: &str) -> String {
+        let mut spelled = String::new();
+        let mut node = Some(self);
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let found = match node {
+                Some(SourcedNode::Dir { children, .. }) => {
+                    children.iter().find(|(existing, _)| eq_ignore_case(existing, name))
+                }
+                _ => None,
+            };
+            spelled.push('/');
+            match found {
+                Some((existing, child)) => {
+                    spelled.push_str(existing);
+                    node = Some(child);
+                }
+                None => {
+                    spelled.push_str(name);
+                    node = None;
+                }
+            }
+        }
+        spelled
+    }
+
+    /// Collect every mod that provides a file in this tree, once each.
+    fn sources(&self, sources: &mut Vec<Uuid>) {
+        match self {
+            SourcedNode::Dir { children, .. } => {
+                for node in children.values() {
+                    node.sources(sources);
+                }
+            }
+            SourcedNode::File { source, .. } => {
+                if !sources.contains(source) {
+                    sources.push(*source);
+                }
             }
-            _ => {}
         }
     }
 
@@ -139,9 +450,9 @@ impl SourcedNode {
                             SourcedNode::Dir { .. } => {
                                 node.ops_for_remove_dir(&format!("{}/{}", current_path, name), ops);
                             }
-                            SourcedNode::File { .. } => {
+                            SourcedNode::File { source, .. } => {
                                 ops.push(Operation {
-                                    kind: OperationKind::RemoveFile,
+                                    kind: OperationKind::RemoveFile(*source),
                                     path: format!("{}/{}", current_path, name),
                                 });
                             }
@@ -166,22 +477,39 @@ impl SourcedNode {
             },
             (
                 SourcedNode::File {
-                    name: _,
                     source: old_source,
+                    origin: old_origin,
+                    ..
                 },
                 SourcedNode::File {
-                    name: _,
                     source: new_source,
+                    origin: new_origin,
+                    ..
                 },
             ) => {
-                if old_source != new_source {
+                // a file of the same mod from somewhere else in it, like another option, is a change of source too
+                if old_source != new_source || old_origin != new_origin {
                     ops.push(Operation {
-                        kind: OperationKind::ChangeSource(*new_source),
+                        kind: OperationKind::ChangeSource {
+                            from: *old_source,
+                            to: *new_source,
+                        },
                         path: current_path.to_string(),
                     });
                 }
             }
-            _ => unreachable!("SourcedNode::difference"),
+            // a file became a dir or the other way round: remove the old one completely before creating the
+            // new one in its place
+            (old, new) => {
+                match old {
+                    SourcedNode::Dir { .. } => old.ops_for_remove_dir(current_path, ops),
+                    SourcedNode::File { source, .. } => ops.push(Operation {
+                        kind: OperationKind::RemoveFile(*source),
+                        path: current_path.to_string(),
+                    }),
+                }
+                new.ops_for_create_dir(current_path, ops);
+            }
         }
     }
 
@@ -197,7 +525,7 @@ impl SourcedNode {
                     node.ops_for_create_dir(&format!("{}/{}", path, name), ops);
                 }
             }
-            SourcedNode::File { name: _, source } => {
+            SourcedNode::File { source, .. } => {
                 ops.push(Operation {
                     kind: OperationKind::CreateFile(*source),
                     path: path.to_string(),
@@ -218,15 +546,105 @@ impl SourcedNode {
                     path: path.to_string(),
                 });
             }
-            SourcedNode::File { name: _, source: _ } => {
+            SourcedNode::File { source, .. } => {
                 ops.push(Operation {
-                    kind: OperationKind::RemoveFile,
+                    kind: OperationKind::RemoveFile(*source),
                     path: path.to_string(),
                 });
             }
         }
     }
 
+    /// Collect the path and source of every file in the tree. Paths start with a `/`, like the paths of
+    /// operations.
+    pub(crate) fn files(&self, current_path: &str, files: &mut Vec<(String, Uuid)>) {
+        match self {
+            SourcedNode::Dir { children, .. } => {
+                for (name, node) in children {
+                    node.files(&format!("{}/{}", current_path, name), files);
+                }
+            }
+            SourcedNode::File { source, .. } => files.push((current_path.to_string(), *source)),
+        }
+    }
+
+    /// The node at `path`, a path like those of operations, if the tree has one there.
+    pub(crate) fn node_at(&self, path: &str) -> Option<&SourcedNode> {
+        let mut node = self;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            match node {
+                SourcedNode::Dir { children, .. } => node = children.get(name)?,
+                SourcedNode::File { .. } => return None,
+            }
+        }
+        Some(node)
+    }
+
+    /// The files of this tree that come from `source`, as the tree of that mod.
+    pub(crate) fn node_of(&self, source: Uuid) -> Node {
+        match self {
+            SourcedNode::Dir { name, children } => Node::Dir {
+                name: name.clone(),
+                children: children
+                    .iter()
+                    .map(|(name, node)| (name.clone(), node.node_of(source)))
+                    .filter(|(_, node)| match node {
+                        Node::Dir { children, .. } => !children.is_empty(),
+                        Node::File { .. } => true,
+                    })
+                    .collect(),
+            },
+            SourcedNode::File {
+                name,
+                source: file_source,
+                origin,
+            } if *file_source == source => Node::File {
+                name: name.clone(),
+                origin: origin.clone(),
+            },
+            // left out by the dir it is in, like dirs without any file of `source`
+            SourcedNode::File { name, .. } => Node::Dir {
+                name: name.clone(),
+                children: HashMap::new(),
+            },
+        }
+    }
+
+    /// The source of the file at `path`, if the tree has a file there.
+    pub(crate) fn file_source(&self, path: &str) -> Option<Uuid> {
+        match self.node_at(path)? {
+            SourcedNode::File { source, .. } => Some(*source),
+            SourcedNode::Dir { .. } => None,
+        }
+    }
+
+    /// Make the file at `path` come from `source`, found at `origin` in the mod if that isn't `path`. The
+    /// file, and any dirs leading to it, are added if the tree doesn't have them yet.
+    pub(crate) fn set_file_source(&mut self, path: &str, source: Uuid, origin: Option<String>) {
+        let mut node = self;
+        let mut names = path.split('/').filter(|name| !name.is_empty()).peekable();
+        while let Some(name) = names.next() {
+            let SourcedNode::Dir { children, .. } = node else {
+                return;
+            };
+            if names.peek().is_none() {
+                children.insert(
+                    name.to_string(),
+                    SourcedNode::File {
+                        name: name.to_string(),
+                        source,
+                        origin,
+                    },
+                );
+                return;
+            }
+            node = children.entry(name.to_string()).or_insert_with(|| SourcedNode::Dir {
+                name: name.to_string(),
+                children: HashMap::new(),
+            });
+        }
+    }
+
     pub(crate) fn print(&self, ident: usize) {
         match self {
             SourcedNode::Dir { name, children } => {
@@ -235,24 +653,203 @@ impl SourcedNode {
                     node.print(ident + 1);
                 }
             }
-            SourcedNode::File { name, source } => {
+            SourcedNode::File { name, source, .. } => {
                 println!("{}{}: {:?}", "  ".repeat(ident), name, source);
             }
         }
     }
 }
 
+/// A file of one mod that was replaced by the same file of another while building a tree. With
+/// `type_mismatch` set, one of them has a dir at the path and the other a file, and all the files of the
+/// replaced one below the path are gone.
 #[derive(Debug)]
-pub(crate) struct Operation {
-    pub(crate) kind: OperationKind,
+pub(crate) struct Overwrite {
     pub(crate) path: String,
+    pub(crate) replaced: Uuid,
+    pub(crate) by: Uuid,
+    pub(crate) type_mismatch: bool,
 }
 
-#[derive(Debug)]
-pub(crate) enum OperationKind {
+/// A single change to the working directory, computed by diffing the deployed tree against the new one.
+///
+/// `path` is relative to the working directory and always starts with a `/`. Every operation records
+/// enough to be undone, see [`Operation::inverse`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Operation {
+    pub kind: OperationKind,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OperationKind {
     CreateDir,
     RemoveDir,
-    CreateFile(ModKey),
-    RemoveFile,
-    ChangeSource(ModKey),
+    /// Deploy the file from the given mod, backing up whatever was there.
+    CreateFile(Uuid),
+    /// Remove the file deployed from the given mod, restoring the backup if there is one.
+    RemoveFile(Uuid),
+    ChangeSource { from: Uuid, to: Uuid },
+}
+
+impl Operation {
+    /// The operation that undoes this one.
+    pub fn inverse(&self) -> Operation {
+        let kind = match self.kind {
+            OperationKind::CreateDir => OperationKind::RemoveDir,
+            OperationKind::RemoveDir => OperationKind::CreateDir,
+            OperationKind::CreateFile(source) => OperationKind::RemoveFile(source),
+            OperationKind::RemoveFile(source) => OperationKind::CreateFile(source),
+            OperationKind::ChangeSource { from, to } => OperationKind::ChangeSource { from: to, to: from },
+        };
+        Operation {
+            kind,
+            path: self.path.clone(),
+        }
+    }
+}
+
+impl OperationKind {
+    /// The mod whose file the operation deploys, if it deploys one.
+    pub fn source(&self) -> Option<Uuid> {
+        match *self {
+            OperationKind::CreateFile(source) | OperationKind::ChangeSource { to: source, .. } => Some(source),
+            OperationKind::CreateDir | OperationKind::RemoveDir | OperationKind::RemoveFile(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            OperationKind::CreateDir => write!(f, "create dir {}", self.path),
+            OperationKind::RemoveDir => write!(f, "remove dir {}", self.path),
+            OperationKind::CreateFile(source) => write!(f, "create file {} from {}", self.path, source),
+            OperationKind::RemoveFile(source) => write!(f, "remove file {} from {}", self.path, source),
+            OperationKind::ChangeSource { from, to } => {
+                write!(f, "change source of {} from {} to {}", self.path, from, to)
+            }
+        }
+    }
+}
+
+/// Whether two names are the same, ignoring case.
+pub(crate) fn eq_ignore_case(a: &str, b: &str) -> bool {
+    a.chars().flat_map(char::to_lowercase).eq(b.chars().flat_map(char::to_lowercase))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str) -> Node {
+        Node::File {
+            name: name.to_string(),
+            origin: None,
+        }
+    }
+
+    fn dir(name: &str, children: Vec<Node>) -> Node {
+        Node::Dir {
+            name: name.to_string(),
+            children: children.into_iter().map(|node| (node.name().to_string(), node)).collect(),
+        }
+    }
+
+    /// The tree of the mods `nodes`, laid over each other in order, and the overwrites that happened.
+    fn merged(nodes: &[(&Node, Uuid)], case_insensitive: bool) -> (SourcedNode, Vec<Overwrite>) {
+        let mut tree = SourcedNode::from_node(&dir("root", vec![]), Uuid::nil());
+        let mut overwrites = Vec::new();
+        for (node, source) in nodes {
+            tree.overwrite_with(node, *source, "", &mut overwrites, case_insensitive);
+        }
+        (tree, overwrites)
+    }
+
+    fn ops(old: &SourcedNode, new: &SourcedNode) -> Vec<String> {
+        let mut ops = Vec::new();
+        old.tree_edit_distance(new, &mut ops, "");
+        ops.iter().map(|op| op.to_string()).collect()
+    }
+
+    #[test]
+    fn dir_replaces_file_of_an_earlier_mod() {
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let with_file = dir("root", vec![file("data")]);
+        let with_dir = dir("root", vec![dir("data", vec![file("x.txt")])]);
+        let (tree, overwrites) = merged(&[(&with_file, a), (&with_dir, b)], false);
+        assert!(matches!(tree.node_at("/data"), Some(SourcedNode::Dir { .. })));
+        assert_eq!(tree.file_source("/data/x.txt"), Some(b));
+        assert_eq!(overwrites.len(), 1);
+        let overwrite = &overwrites[0];
+        assert_eq!((overwrite.path.as_str(), overwrite.replaced, overwrite.by), ("/data", a, b));
+        assert!(overwrite.type_mismatch);
+    }
+
+    #[test]
+    fn file_replaces_dir_of_an_earlier_mod() {
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let with_dir = dir("root", vec![dir("data", vec![file("x.txt"), dir("deep", vec![file("y.txt")])])]);
+        let with_file = dir("root", vec![file("data")]);
+        let (tree, overwrites) = merged(&[(&with_dir, a), (&with_file, b)], false);
+        assert_eq!(tree.file_source("/data"), Some(b));
+        assert!(tree.node_at("/data/x.txt").is_none());
+        // every mod that loses files below the path is reported once
+        assert_eq!(overwrites.len(), 1);
+        let overwrite = &overwrites[0];
+        assert_eq!((overwrite.path.as_str(), overwrite.replaced, overwrite.by), ("/data", a, b));
+        assert!(overwrite.type_mismatch);
+    }
+
+    #[test]
+    fn type_changes_remove_before_creating() {
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let as_file = merged(&[(&dir("root", vec![file("data")]), a)], false).0;
+        let as_dir = merged(&[(&dir("root", vec![dir("data", vec![file("x.txt")])]), b)], false).0;
+        assert_eq!(
+            ops(&as_file, &as_dir),
+            [
+                format!("remove file /data from {}", a),
+                "create dir /data".to_string(),
+                format!("create file /data/x.txt from {}", b),
+            ]
+        );
+        assert_eq!(
+            ops(&as_dir, &as_file),
+            [
+                format!("remove file /data/x.txt from {}", b),
+                "remove dir /data".to_string(),
+                format!("create file /data from {}", a),
+            ]
+        );
+    }
+
+    #[test]
+    fn compares_names_ignoring_case() {
+        assert!(eq_ignore_case("Textures", "tEXTURES"));
+        assert!(eq_ignore_case("Émile.ini", "émile.INI"));
+        assert!(!eq_ignore_case("a.txt", "a.txt.bak"));
+        assert!(!eq_ignore_case("a.txt", "b.txt"));
+    }
+
+    #[test]
+    fn case_insensitive_merge_keeps_the_first_spelling() {
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let first = dir("root", vec![dir("Data", vec![file("a.txt")])]);
+        let second = dir("root", vec![dir("DATA", vec![file("A.TXT"), file("b.txt")])]);
+        let (tree, overwrites) = merged(&[(&first, a), (&second, b)], true);
+        let mut files = Vec::new();
+        tree.files("", &mut files);
+        files.sort();
+        assert_eq!(files, [("/Data/a.txt".to_string(), b), ("/Data/b.txt".to_string(), b)]);
+        assert_eq!(overwrites.len(), 1);
+        assert_eq!((overwrites[0].path.as_str(), overwrites[0].replaced), ("/Data/a.txt", a));
+        assert_eq!(tree.spelling_of("/data/A.txt/x"), "/Data/a.txt/x");
+
+        // merged as two paths otherwise
+        let (tree, overwrites) = merged(&[(&first, a), (&second, b)], false);
+        assert_eq!(tree.file_source("/Data/a.txt"), Some(a));
+        assert_eq!(tree.file_source("/DATA/A.TXT"), Some(b));
+        assert!(overwrites.is_empty());
+    }
 }
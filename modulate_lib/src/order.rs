@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use uuid::Uuid;
+
+/// An ordering rule between two mods: `before` is loaded before `after`, so files of `after` win over
+/// files of `before`. In the list of active mods, `after` is placed ahead of `before`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoadRule {
+    pub before: Uuid,
+    pub after: Uuid,
+}
+
+/// Sort `items` so that for every `(first, second)` edge `first` comes before `second`, keeping the
+/// original order wherever the edges allow it. Edges between unknown items are ignored.
+///
+/// On a cycle, returns the items that form it, with the first item repeated at the end.
+pub(crate) fn stable_topological_sort(items: &[Uuid], edges: &[(Uuid, Uuid)]) -> Result<Vec<Uuid>, Vec<Uuid>> {
+    let index: HashMap<Uuid, usize> = items.iter().enumerate().map(|(i, &uuid)| (uuid, i)).collect();
+    let mut successors = vec![Vec::new(); items.len()];
+    let mut predecessors = vec![0; items.len()];
+    for (first, second) in edges {
+        if let (Some(&first), Some(&second)) = (index.get(first), index.get(second)) {
+            successors[first].push(second);
+            predecessors[second] += 1;
+        }
+    }
+
+    // always take the earliest item that has nothing left before it
+    let mut ready: BTreeSet<usize> = (0..items.len()).filter(|&i| predecessors[i] == 0).collect();
+    let mut sorted = Vec::with_capacity(items.len());
+    while let Some(i) = ready.pop_first() {
+        sorted.push(items[i]);
+        for &next in &successors[i] {
+            predecessors[next] -= 1;
+            if predecessors[next] == 0 {
+                ready.insert(next);
+            }
+        }
+    }
+    if sorted.len() == items.len() {
+        return Ok(sorted);
+    }
+
+    // every item left has a predecessor that is also left, so walking backwards must loop
+    let mut chain = Vec::new();
+    let mut seen = HashMap::new();
+    let mut current = (0..items.len()).find(|&i| predecessors[i] > 0).unwrap();
+    loop {
+        if let Some(&start) = seen.get(&current) {
+            let mut cycle: Vec<Uuid> = chain[start..].iter().rev().map(|&i| items[i]).collect();
+            cycle.push(cycle[0]);
+            return Err(cycle);
+        }
+        seen.insert(current, chain.len());
+        chain.push(current);
+        current = (0..items.len())
+            .find(|&i| predecessors[i] > 0 && successors[i].contains(&current))
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(n: u128) -> Vec<Uuid> {
+        (0..n).map(Uuid::from_u128).collect()
+    }
+
+    #[test]
+    fn keeps_order_without_edges() {
+        let items = ids(4);
+        assert_eq!(stable_topological_sort(&items, &[]), Ok(items.clone()));
+    }
+
+    #[test]
+    fn moves_only_what_the_edges_require() {
+        let items = ids(5);
+        // 3 must come before 1; everything else stays where it was
+        let sorted = stable_topological_sort(&items, &[(items[3], items[1])]).unwrap();
+        assert_eq!(sorted, [items[0], items[2], items[3], items[1], items[4]]);
+
+        let edges = [(items[4], items[0]), (items[2], items[4])];
+        let sorted = stable_topological_sort(&items, &edges).unwrap();
+        assert_eq!(sorted, [items[1], items[2], items[3], items[4], items[0]]);
+    }
+
+    #[test]
+    fn satisfied_edges_change_nothing() {
+        let items = ids(4);
+        let edges = [(items[0], items[2]), (items[1], items[3])];
+        assert_eq!(stable_topological_sort(&items, &edges), Ok(items.clone()));
+    }
+
+    #[test]
+    fn ignores_unknown_items() {
+        let items = ids(3);
+        let unknown = Uuid::from_u128(99);
+        let edges = [(unknown, items[0]), (items[2], unknown)];
+        assert_eq!(stable_topological_sort(&items, &edges), Ok(items.clone()));
+    }
+
+    #[test]
+    fn reports_cycle_as_chain() {
+        let items = ids(4);
+        let edges = [(items[1], items[2]), (items[2], items[3]), (items[3], items[1])];
+        let cycle = stable_topological_sort(&items, &edges).unwrap_err();
+        assert_eq!(cycle.len(), 4);
+        assert_eq!(cycle.first(), cycle.last());
+        // every step of the chain is an edge
+        for pair in cycle.windows(2) {
+            assert!(edges.contains(&(pair[0], pair[1])), "{:?} is not an edge", pair);
+        }
+        assert!(!cycle.contains(&items[0]));
+    }
+
+    #[test]
+    fn reports_self_loop() {
+        let items = ids(3);
+        let cycle = stable_topological_sort(&items, &[(items[1], items[1])]).unwrap_err();
+        assert_eq!(cycle, [items[1], items[1]]);
+    }
+
+    #[test]
+    fn reports_only_the_cycle_behind_other_items() {
+        let items = ids(4);
+        // 0 waits on the cycle between 2 and 3 but isn't part of it
+        let edges = [(items[2], items[0]), (items[2], items[3]), (items[3], items[2])];
+        let cycle = stable_topological_sort(&items, &edges).unwrap_err();
+        assert_eq!(cycle.len(), 3);
+        assert_eq!(cycle.first(), cycle.last());
+        assert!(cycle.contains(&items[2]) && cycle.contains(&items[3]));
+    }
+}
@@ -0,0 +1,214 @@
+/// A unified diff, as written by `diff -u` or `git diff`, for a single file. The file headers are skipped:
+/// which file a patch changes comes from where it is in the mod, not from what it says.
+pub(crate) struct Patch {
+    hunks: Vec<Hunk>,
+}
+
+struct Hunk {
+    /// The `@@ -1,3 +1,4 @@` line, for reporting the hunk.
+    header: String,
+    /// The line the hunk starts at in the original file, counting from 0.
+    start: usize,
+    old: Vec<String>,
+    new: Vec<String>,
+}
+
+impl Patch {
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let mut hunks = Vec::new();
+        let mut lines = text.lines().map(|line| line.strip_suffix('\r').unwrap_or(line)).enumerate();
+        while let Some((n, header)) = lines.next() {
+            // anything outside of a hunk is a header or a comment
+            let Some(range) = header.strip_prefix("@@ -") else {
+                continue;
+            };
+            let invalid = || format!("invalid hunk header on line {}: {}", n + 1, header);
+            let (ranges, _) = range.split_once(" @@").ok_or_else(invalid)?;
+            let (old_range, new_range) = ranges.split_once(" +").ok_or_else(invalid)?;
+            let (old_start, mut old_len) = parse_range(old_range).ok_or_else(invalid)?;
+            let (_, mut new_len) = parse_range(new_range).ok_or_else(invalid)?;
+            let mut hunk = Hunk {
+                header: header.to_string(),
+                // an empty range is given as the line before it
+                start: if old_len == 0 { old_start } else { old_start.saturating_sub(1) },
+                old: Vec::new(),
+                new: Vec::new(),
+            };
+            while old_len > 0 || new_len > 0 {
+                let Some((n, line)) = lines.next() else {
+                    return Err(format!("hunk {} ends early", header));
+                };
+                // some tools strip the space of empty context lines
+                let (kind, content) = match line.chars().next() {
+                    Some(kind) => (kind, &line[kind.len_utf8()..]),
+                    None => (' ', ""),
+                };
+                match kind {
+                    ' ' if old_len > 0 && new_len > 0 => {
+                        hunk.old.push(content.to_string());
+                        hunk.new.push(content.to_string());
+                        old_len -= 1;
+                        new_len -= 1;
+                    }
+                    '-' if old_len > 0 => {
+                        hunk.old.push(content.to_string());
+                        old_len -= 1;
+                    }
+                    '+' if new_len > 0 => {
+                        hunk.new.push(content.to_string());
+                        new_len -= 1;
+                    }
+                    '\\' => {}
+                    _ => return Err(format!("unexpected line {} in hunk: {}", n + 1, line)),
+                }
+            }
+            hunks.push(hunk);
+        }
+        if hunks.is_empty() {
+            return Err("no hunks".to_string());
+        }
+        Ok(Self { hunks })
+    }
+
+    /// Apply the patch to `text`, and return the result with the headers of the hunks that didn't apply.
+    ///
+    /// A hunk applies where its context and removed lines match, at the line it gives or, if the file
+    /// changed since the patch was made, at the closest line to it after the hunks before. Lines are compared
+    /// without their line endings, so a patch made on a file with LF endings applies to one with CRLF.
+    pub(crate) fn apply(&self, text: &str) -> (String, Vec<String>) {
+        let mut lines: Vec<&str> = text.lines().map(|line| line.strip_suffix('\r').unwrap_or(line)).collect();
+        let mut rejected = Vec::new();
+        // how far the lines have moved from where the patch expects them, and the first line a hunk can
+        // still change
+        let mut offset = 0isize;
+        let mut min = 0;
+        for hunk in &self.hunks {
+            let expected = (hunk.start as isize + offset).clamp(min as isize, lines.len() as isize) as usize;
+            let matches = |at: usize| {
+                lines.get(at..at + hunk.old.len()).is_some_and(|window| window.iter().eq(hunk.old.iter()))
+            };
+            let found = (0..=lines.len()).find_map(|distance| {
+                let after = expected + distance;
+                let before = expected.checked_sub(distance).filter(|&at| at >= min);
+                [before, Some(after)].into_iter().flatten().find(|&at| matches(at))
+            });
+            let Some(at) = found else {
+                rejected.push(hunk.header.clone());
+                continue;
+            };
+            lines.splice(at..at + hunk.old.len(), hunk.new.iter().map(String::as_str));
+            offset = at as isize - hunk.start as isize + hunk.new.len() as isize - hunk.old.len() as isize;
+            min = at + hunk.new.len();
+        }
+        let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+        let mut patched = lines.join(newline);
+        if !lines.is_empty() && (text.is_empty() || text.ends_with('\n')) {
+            patched.push_str(newline);
+        }
+        (patched, rejected)
+    }
+}
+
+/// A `start,len` range of a hunk header, where the length is 1 if it is left out.
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    match range.split_once(',') {
+        Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+        None => Some((range.parse().ok()?, 1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(patch: &str, text: &str) -> (String, Vec<String>) {
+        Patch::parse(patch).unwrap().apply(text)
+    }
+
+    const BASE: &str = "one\ntwo\nthree\nfour\nfive\nsix\nseven\n";
+
+    #[test]
+    fn applies_hunks() {
+        let patch = concat!(
+            "--- a/cfg\n+++ b/cfg\n",
+            "@@ -1,3 +1,3 @@\n one\n-two\n+TWO\n three\n",
+            "@@ -5,3 +5,4 @@\n five\n six\n+six and a half\n seven\n",
+        );
+        let (patched, rejected) = apply(patch, BASE);
+        assert_eq!(patched, "one\nTWO\nthree\nfour\nfive\nsix\nsix and a half\nseven\n");
+        assert!(rejected.is_empty());
+    }
+
+    #[test]
+    fn finds_moved_lines() {
+        let patch = "@@ -1,3 +1,3 @@\n one\n-two\n+TWO\n three\n@@ -5,3 +5,3 @@\n five\n-six\n+SIX\n seven\n";
+        let (patched, rejected) = apply(patch, &format!("zero\nhalf\n{}", BASE));
+        assert_eq!(patched, "zero\nhalf\none\nTWO\nthree\nfour\nfive\nSIX\nseven\n");
+        assert!(rejected.is_empty());
+
+        // lines that moved up
+        let (patched, _) = apply("@@ -5,3 +5,3 @@\n five\n-six\n+SIX\n seven\n", "three\nfour\nfive\nsix\nseven\n");
+        assert_eq!(patched, "three\nfour\nfive\nSIX\nseven\n");
+    }
+
+    #[test]
+    fn rejects_hunks_that_dont_match() {
+        let patch = concat!(
+            "@@ -1,2 +1,2 @@\n-one\n+ONE\n two\n",
+            "@@ -4,1 +4,1 @@\n-missing\n+found\n",
+            "@@ -6,1 +6,1 @@\n-six\n+SIX\n",
+        );
+        let (patched, rejected) = apply(patch, BASE);
+        assert_eq!(patched, "ONE\ntwo\nthree\nfour\nfive\nSIX\nseven\n");
+        assert_eq!(rejected, ["@@ -4,1 +4,1 @@"]);
+    }
+
+    #[test]
+    fn hunks_dont_apply_before_earlier_ones() {
+        // the second hunk only matches before the first one
+        let patch = "@@ -3,1 +3,1 @@\n-x\n+y\n@@ -5,1 +5,1 @@\n-a\n+b\n";
+        let (patched, rejected) = apply(patch, "a\n\nx\n\n\n");
+        assert_eq!(patched, "a\n\ny\n\n\n");
+        assert_eq!(rejected, ["@@ -5,1 +5,1 @@"]);
+    }
+
+    #[test]
+    fn keeps_crlf() {
+        let patch = "@@ -1,2 +1,2 @@\r\n one\r\n-two\r\n+TWO\r\n";
+        assert_eq!(apply(patch, "one\r\ntwo\r\n").0, "one\r\nTWO\r\n");
+        // a patch with LF endings applies to a file with CRLF endings
+        let patch = "@@ -1,2 +1,2 @@\n one\n-two\n+TWO\n";
+        assert_eq!(apply(patch, "one\r\ntwo\r\n").0, "one\r\nTWO\r\n");
+    }
+
+    #[test]
+    fn handles_missing_newline_at_end() {
+        let patch = "@@ -1,2 +1,2 @@\n one\n-two\n\\ No newline at end of file\n+TWO\n\\ No newline at end of file\n";
+        assert_eq!(apply(patch, "one\ntwo").0, "one\nTWO");
+    }
+
+    #[test]
+    fn handles_empty_ranges() {
+        // a new file
+        assert_eq!(apply("--- /dev/null\n+++ b/cfg\n@@ -0,0 +1,2 @@\n+a\n+b\n", "").0, "a\nb\n");
+        // lines added after line 2, without context
+        assert_eq!(apply("@@ -2,0 +3 @@\n+new\n", "one\ntwo\nthree\n").0, "one\ntwo\nnew\nthree\n");
+        // lines removed, with the length left out
+        assert_eq!(apply("@@ -2 +1,0 @@\n-two\n", "one\ntwo\nthree\n").0, "one\nthree\n");
+    }
+
+    #[test]
+    fn accepts_empty_context_lines_without_space() {
+        let patch = "@@ -1,3 +1,3 @@\n a\n\n-b\n+B\n";
+        assert_eq!(apply(patch, "a\n\nb\n").0, "a\n\nB\n");
+    }
+
+    #[test]
+    fn rejects_malformed_patches() {
+        assert!(Patch::parse("just some text\n").is_err());
+        assert!(Patch::parse("@@ -x +1 @@\n").is_err());
+        assert!(Patch::parse("@@ -1,2 +1,2 @@\n one\n").is_err());
+        assert!(Patch::parse("@@ -1,1 +1,1 @@\n*one\n").is_err());
+        assert!(Patch::parse("@@ -1,1 +1,1 @@\néone\n").is_err());
+    }
+}
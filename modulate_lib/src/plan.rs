@@ -0,0 +1,61 @@
+use crate::merge::GeneratedFile;
+use crate::node::{Operation, SourcedNode};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Everything a deploy would do, computed by [`ModManager::plan_deploy`](crate::ModManager::plan_deploy)
+/// without touching the disk, and carried out by [`ModManager::apply_plan`](crate::ModManager::apply_plan).
+///
+/// A plan only applies to the deployment it was made against. Once anything else is deployed it is
+/// stale and has to be made again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeployPlan {
+    pub operations: Vec<PlannedOperation>,
+    /// The hunks of mod patches that didn't apply. The files are deployed with the rest of their patches.
+    pub rejected_hunks: Vec<RejectedHunk>,
+    pub(crate) base_tree: SourcedNode,
+    pub(crate) new_tree: SourcedNode,
+    pub(crate) generated: Vec<GeneratedFile>,
+}
+
+impl DeployPlan {
+    /// Whether the plan changes nothing, i.e. the working directory is already up to date.
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+}
+
+/// One step of a [`DeployPlan`], with the paths it touches resolved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlannedOperation {
+    pub operation: Operation,
+    /// The path in the working directory that is changed.
+    pub target: PathBuf,
+    /// The mod file that ends up at `target`, for operations that deploy one.
+    pub source: Option<PlannedFile>,
+    /// The mod file that was at `target` before, for operations that replace or remove one. `None` if that
+    /// mod is no longer registered; its uuid is still in `operation`.
+    pub previous: Option<PlannedFile>,
+}
+
+/// A file of a specific mod.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlannedFile {
+    pub mod_uuid: Uuid,
+    pub mod_name: String,
+    pub path: PathBuf,
+}
+
+/// A hunk of a mod patch that didn't apply, because the file it patches doesn't have the lines it changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RejectedHunk {
+    /// The patched file, relative to the working directory.
+    pub target: PathBuf,
+    pub mod_uuid: Uuid,
+    pub mod_name: String,
+    /// The patch file, relative to the mod directory.
+    pub patch: PathBuf,
+    /// The `@@` line the hunk starts with.
+    pub hunk: String,
+}
@@ -0,0 +1,57 @@
+use crate::node::SourcedNode;
+use crate::order::LoadRule;
+use crate::r#mod::ModSettings;
+use crate::verify::FileStamp;
+use crate::{DeployStrategy, ModError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// A mod as it is remembered between runs: the uuid it had when it was registered, the
+/// directory it was loaded from, the deploy strategy set for it, if any, and the user's settings for it.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct StoredMod {
+    pub(crate) uuid: Uuid,
+    pub(crate) dir: PathBuf,
+    pub(crate) strategy: Option<DeployStrategy>,
+    pub(crate) settings: ModSettings,
+}
+
+/// Everything the manager needs to pick up where a previous process left off.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct State {
+    pub(crate) active_mods: Vec<StoredMod>,
+    pub(crate) inactive_mods: Vec<StoredMod>,
+    pub(crate) overwrite_mod: StoredMod,
+    pub(crate) deployed_tree: SourcedNode,
+    pub(crate) load_rules: Vec<LoadRule>,
+    pub(crate) deploy_strategy: DeployStrategy,
+    pub(crate) case_insensitive: bool,
+    pub(crate) deployed_files: HashMap<String, FileStamp>,
+}
+
+impl State {
+    pub(crate) fn load(path: &Path) -> Result<Option<Self>, ModError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = fs::File::open(path).map_err(|e| ModError::StateLoadFailed(e.to_string()))?;
+        let len = file.metadata().map_err(|e| ModError::StateLoadFailed(e.to_string()))?.len();
+        crate::deserialize_limited(io::BufReader::new(file), len)
+            .map(Some)
+            .map_err(|e| ModError::StateLoadFailed(e.to_string()))
+    }
+
+    /// Write the state to a temporary file first and rename it over the old one, so a crash
+    /// mid-write never leaves a truncated state file behind.
+    pub(crate) fn save(&self, path: &Path) -> Result<(), ModError> {
+        let tmp_path = path.with_extension("state.tmp");
+        let file = fs::File::create(&tmp_path).map_err(|e| ModError::StateSaveFailed(e.to_string()))?;
+        bincode::serialize_into(&file, self).map_err(|e| ModError::StateSaveFailed(e.to_string()))?;
+        file.sync_all().map_err(|e| ModError::StateSaveFailed(e.to_string()))?;
+        fs::rename(tmp_path, path).map_err(|e| ModError::StateSaveFailed(e.to_string()))
+    }
+}
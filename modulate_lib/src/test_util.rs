@@ -0,0 +1,27 @@
+//! Fixtures shared by the tests of every module.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A scratch dir for one test, deleted when dropped, so that it is cleaned up even when the test fails.
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create an empty dir. Tests run at the same time, so `name` has to be unique across all of them.
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("modulate-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+use uuid::Uuid;
+
+/// What a deployed file looked like right after it was deployed. Writing to the file in place changes its
+/// size or modification time, replacing it changes its inode.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FileStamp {
+    inode: u64,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    /// The stamp of the file at `path`, following symlinks, or `None` if there is nothing there.
+    pub(crate) fn of(path: &Path) -> io::Result<Option<Self>> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+        Ok(Some(Self {
+            inode,
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        }))
+    }
+
+    /// How the file stamped `now` differs from this stamp, if it does.
+    pub(crate) fn compare(&self, now: Option<&FileStamp>) -> Option<ChangeKind> {
+        match now {
+            None => Some(ChangeKind::Removed),
+            Some(now) if now.inode != self.inode => Some(ChangeKind::Replaced),
+            Some(now) if now != self => Some(ChangeKind::Edited { source_modified: false }),
+            Some(_) => None,
+        }
+    }
+}
+
+/// A deployed file that was changed by something other than the manager, found by
+/// [`ModManager::verify`](crate::ModManager::verify).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangedFile {
+    /// The path in the working directory, starting with a `/`.
+    pub path: String,
+    /// The mod the file was deployed from.
+    pub source: Uuid,
+    pub kind: ChangeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    /// The deployed file is gone.
+    Removed,
+    /// Another file was put in place of the deployed one. The mod file is untouched.
+    Replaced,
+    /// The deployed file was written to in place. If it is a hard link or a symlink, that changed the
+    /// file in the mod as well, and `source_modified` is set.
+    Edited { source_modified: bool },
+}
+
+/// What to do about a [`ChangedFile`], see [`ModManager::resolve_change`](crate::ModManager::resolve_change).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Throw the change away and deploy the mod file again. Not possible if the mod file itself was
+    /// edited, since the original is gone.
+    Restore,
+    /// Move the changed file into the given mod, at the same path, and deploy the mod file again. The
+    /// change comes back on the next deploy if that mod wins the file.
+    MoveTo(Uuid),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::io::Write;
+    use std::time::Duration;
+
+    fn stamp(inode: u64, size: u64, modified: u64) -> FileStamp {
+        FileStamp {
+            inode,
+            size,
+            modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(modified)),
+        }
+    }
+
+    #[test]
+    fn compares_stamps() {
+        let deployed = stamp(1, 10, 100);
+        assert_eq!(deployed.compare(Some(&stamp(1, 10, 100))), None);
+        assert_eq!(deployed.compare(None), Some(ChangeKind::Removed));
+        // a new file is replaced, whatever its size
+        assert_eq!(deployed.compare(Some(&stamp(2, 10, 100))), Some(ChangeKind::Replaced));
+        assert_eq!(deployed.compare(Some(&stamp(2, 20, 200))), Some(ChangeKind::Replaced));
+        let edited = Some(ChangeKind::Edited { source_modified: false });
+        assert_eq!(deployed.compare(Some(&stamp(1, 20, 100))), edited);
+        assert_eq!(deployed.compare(Some(&stamp(1, 10, 200))), edited);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stamps_files_on_disk() {
+        let tmp = TempDir::new("verify-stamps");
+        let dir = tmp.path();
+        let path = dir.join("a.txt");
+        assert_eq!(FileStamp::of(&path).unwrap(), None);
+        fs::write(&path, "a").unwrap();
+        let deployed = FileStamp::of(&path).unwrap().unwrap();
+        assert_eq!(deployed.compare(FileStamp::of(&path).unwrap().as_ref()), None);
+
+        fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(b"b").unwrap();
+        let edited = FileStamp::of(&path).unwrap();
+        assert_eq!(deployed.compare(edited.as_ref()), Some(ChangeKind::Edited { source_modified: false }));
+        // the replacement is written before the file is removed, so it can't get the same inode
+        fs::write(dir.join("new.txt"), "a").unwrap();
+        fs::rename(dir.join("new.txt"), &path).unwrap();
+        let replaced = FileStamp::of(&path).unwrap();
+        assert_eq!(deployed.compare(replaced.as_ref()), Some(ChangeKind::Replaced));
+    }
+}